@@ -0,0 +1,106 @@
+/* The C API of dystonse-curves, link with -ldystonse_curves_c. */
+
+#ifndef DYSTONSE_CURVES_H
+#define DYSTONSE_CURVES_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The number of values written by `dc_curve_summary`.
+ */
+#define DC_SUMMARY_LEN 5
+
+/**
+ * An opaque curve, created by `dc_curve_from_compact` and freed by `dc_curve_free`.
+ */
+typedef struct DcCurve DcCurve;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Decode a curve from the format of `IrregularDynamicCurve::serialize_compact`.
+ * Returns null if the bytes are not a valid curve.
+ *
+ * # Safety
+ * `bytes` must point to `len` readable bytes.
+ */
+struct DcCurve *dc_curve_from_compact(const uint8_t *bytes, size_t len);
+
+/**
+ * Free a curve. Does nothing if curve is null.
+ *
+ * # Safety
+ * `curve` must have been returned by `dc_curve_from_compact` and not been freed before.
+ */
+void dc_curve_free(struct DcCurve *curve);
+
+/**
+ * The smallest x value of the curve, or NaN if curve is null.
+ *
+ * # Safety
+ * `curve` must be null or a valid curve.
+ */
+float dc_curve_min_x(const struct DcCurve *curve);
+
+/**
+ * The largest x value of the curve, or NaN if curve is null.
+ *
+ * # Safety
+ * `curve` must be null or a valid curve.
+ */
+float dc_curve_max_x(const struct DcCurve *curve);
+
+/**
+ * The probability that the value is at most x, or NaN if curve is null.
+ *
+ * # Safety
+ * `curve` must be null or a valid curve.
+ */
+float dc_curve_y_at_x(const struct DcCurve *curve, float x);
+
+/**
+ * The x value at which the curve reaches y, or NaN if curve is null or y is not
+ * between 0 and 1.
+ *
+ * # Safety
+ * `curve` must be null or a valid curve.
+ */
+float dc_curve_x_at_y(const struct DcCurve *curve, float y);
+
+/**
+ * Write the x values for the `len` y values from `ys` into `xs`.
+ * Returns 0 on success and -1 on errors, in which case `xs` is left unchanged.
+ *
+ * # Safety
+ * `curve` must be null or a valid curve, and `ys` and `xs` must point to `len` floats.
+ */
+int32_t dc_curve_quantiles(const struct DcCurve *curve, const float *ys, float *xs, size_t len);
+
+/**
+ * Write the x values at y = 0.05, 0.25, 0.5, 0.75 and 0.95 into `xs`.
+ * Returns 0 on success and -1 on errors, in which case `xs` is left unchanged.
+ *
+ * # Safety
+ * `curve` must be null or a valid curve, and `xs` must point to `DC_SUMMARY_LEN` floats.
+ */
+int32_t dc_curve_summary(const struct DcCurve *curve, float *xs);
+
+/**
+ * A description of the error of the last call of a `dc_` function on the current thread, or
+ * null if it succeeded. The string is valid until the next call of a `dc_` function on this thread.
+ */
+const char *dc_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* DYSTONSE_CURVES_H */
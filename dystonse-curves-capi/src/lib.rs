@@ -0,0 +1,228 @@
+//! A C API for evaluating curves, see `include/dystonse_curves.h`, which is generated by
+//! cbindgen from this file (see the README). All functions are safe to call with
+//! null pointers, and never unwind into the caller: errors (including panics) are
+//! reported through the return value, and described by `dc_last_error`.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use curves::{Curve, IrregularDynamicCurve, QUANTILES};
+
+/// The number of values written by `dc_curve_summary`.
+pub const DC_SUMMARY_LEN: usize = 5;
+
+const _: () = assert!(QUANTILES.len() == DC_SUMMARY_LEN);
+
+/// An opaque curve, created by `dc_curve_from_compact` and freed by `dc_curve_free`.
+pub struct DcCurve(IrregularDynamicCurve<f32, f32>);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Run f, turning errors and panics into `on_error` and storing their message. The message
+/// of an earlier error is cleared first, so that it's only reported until the next call.
+fn guard<R>(on_error: R, f: impl FnOnce() -> Result<R, String>) -> R {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(message)) => {
+            set_error(message);
+            on_error
+        },
+        Err(panic) => {
+            let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_error(format!("Internal error: {}", message));
+            on_error
+        }
+    }
+}
+
+unsafe fn curve<'a>(curve: *const DcCurve) -> Result<&'a IrregularDynamicCurve<f32, f32>, String> {
+    match curve.as_ref() {
+        Some(curve) => Ok(&curve.0),
+        None => Err("curve is null".to_string())
+    }
+}
+
+fn check_y(y: f32) -> Result<(), String> {
+    if !(0.0..=1.0).contains(&y) {
+        return Err(format!("y must be between 0 and 1, but is {}", y));
+    }
+    Ok(())
+}
+
+/// Decode a curve from the format of `IrregularDynamicCurve::serialize_compact`.
+/// Returns null if the bytes are not a valid curve.
+///
+/// # Safety
+/// `bytes` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_from_compact(bytes: *const u8, len: usize) -> *mut DcCurve {
+    guard(ptr::null_mut(), || {
+        if bytes.is_null() {
+            return Err("bytes is null".to_string());
+        }
+        let bytes = slice::from_raw_parts(bytes, len);
+        let curve = IrregularDynamicCurve::try_deserialize_compact(bytes).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(DcCurve(curve))))
+    })
+}
+
+/// Free a curve. Does nothing if curve is null.
+///
+/// # Safety
+/// `curve` must have been returned by `dc_curve_from_compact` and not been freed before.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_free(curve: *mut DcCurve) {
+    guard((), || {
+        if !curve.is_null() {
+            drop(Box::from_raw(curve));
+        }
+        Ok(())
+    });
+}
+
+/// The smallest x value of the curve, or NaN if curve is null.
+///
+/// # Safety
+/// `curve` must be null or a valid curve.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_min_x(curve: *const DcCurve) -> f32 {
+    guard(f32::NAN, || Ok(self::curve(curve)?.min_x()))
+}
+
+/// The largest x value of the curve, or NaN if curve is null.
+///
+/// # Safety
+/// `curve` must be null or a valid curve.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_max_x(curve: *const DcCurve) -> f32 {
+    guard(f32::NAN, || Ok(self::curve(curve)?.max_x()))
+}
+
+/// The probability that the value is at most x, or NaN if curve is null.
+///
+/// # Safety
+/// `curve` must be null or a valid curve.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_y_at_x(curve: *const DcCurve, x: f32) -> f32 {
+    guard(f32::NAN, || Ok(self::curve(curve)?.y_at_x(x)))
+}
+
+/// The x value at which the curve reaches y, or NaN if curve is null or y is not
+/// between 0 and 1.
+///
+/// # Safety
+/// `curve` must be null or a valid curve.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_x_at_y(curve: *const DcCurve, y: f32) -> f32 {
+    guard(f32::NAN, || {
+        check_y(y)?;
+        Ok(self::curve(curve)?.x_at_y(y))
+    })
+}
+
+/// Write the x values for the `len` y values from `ys` into `xs`.
+/// Returns 0 on success and -1 on errors, in which case `xs` is left unchanged.
+///
+/// # Safety
+/// `curve` must be null or a valid curve, and `ys` and `xs` must point to `len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_quantiles(curve: *const DcCurve, ys: *const f32, xs: *mut f32, len: usize) -> i32 {
+    guard(-1, || {
+        let curve = self::curve(curve)?;
+        if ys.is_null() || xs.is_null() {
+            return Err("ys or xs is null".to_string());
+        }
+        let ys = slice::from_raw_parts(ys, len);
+        ys.iter().try_for_each(|y| check_y(*y))?;
+        let values : Vec<f32> = ys.iter().map(|y| curve.x_at_y(*y)).collect();
+        slice::from_raw_parts_mut(xs, len).copy_from_slice(&values);
+        Ok(0)
+    })
+}
+
+/// Write the x values at y = 0.05, 0.25, 0.5, 0.75 and 0.95 into `xs`.
+/// Returns 0 on success and -1 on errors, in which case `xs` is left unchanged.
+///
+/// # Safety
+/// `curve` must be null or a valid curve, and `xs` must point to `DC_SUMMARY_LEN` floats.
+#[no_mangle]
+pub unsafe extern "C" fn dc_curve_summary(curve: *const DcCurve, xs: *mut f32) -> i32 {
+    let ys : Vec<f32> = QUANTILES.iter().map(|(_, y)| *y).collect();
+    dc_curve_quantiles(curve, ys.as_ptr(), xs, DC_SUMMARY_LEN)
+}
+
+/// A description of the error of the last call of a `dc_` function on the current thread, or
+/// null if it succeeded. The string is valid until the next call of a `dc_` function on this thread.
+#[no_mangle]
+pub extern "C" fn dc_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use curves::Tup;
+    use std::ffi::CStr;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(dc_last_error()) }.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_capi() {
+        let bytes = IrregularDynamicCurve::<f32, f32>::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 100.0, y: 1.0 }]).serialize_compact();
+        unsafe {
+            let curve = dc_curve_from_compact(bytes.as_ptr(), bytes.len());
+            assert!(!curve.is_null());
+            assert_eq!(dc_curve_min_x(curve), 0.0);
+            assert_eq!(dc_curve_max_x(curve), 100.0);
+            assert_eq!(dc_curve_y_at_x(curve, 50.0), 0.5);
+            assert_eq!(dc_curve_x_at_y(curve, 0.25), 25.0);
+
+            let mut summary = [0.0; DC_SUMMARY_LEN];
+            assert_eq!(dc_curve_summary(curve, summary.as_mut_ptr()), 0);
+            assert_eq!(summary, [5.0, 25.0, 50.0, 75.0, 95.0]);
+
+            let mut xs = [0.0; 2];
+            assert_eq!(dc_curve_quantiles(curve, [0.1, 1.5].as_ptr(), xs.as_mut_ptr(), 2), -1);
+            assert!(last_error().contains("between 0 and 1"));
+            assert_eq!(xs, [0.0, 0.0]);
+            assert!(dc_curve_x_at_y(curve, -1.0).is_nan());
+            // a successful call clears the error
+            assert_eq!(dc_curve_x_at_y(curve, 0.25), 25.0);
+            assert!(dc_last_error().is_null());
+            dc_curve_free(curve);
+
+            assert!(dc_curve_from_compact(bytes.as_ptr(), 3).is_null());
+            assert!(last_error().contains("too short"));
+            assert!(dc_curve_from_compact(ptr::null(), 0).is_null());
+            assert!(dc_curve_y_at_x(ptr::null(), 1.0).is_nan());
+            assert_eq!(dc_curve_summary(ptr::null(), summary.as_mut_ptr()), -1);
+            dc_curve_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_header_up_to_date() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::Builder::new().with_crate(dir).with_config(config).generate().unwrap().write(&mut generated);
+        let committed = std::fs::read(format!("{}/include/dystonse_curves.h", dir)).unwrap();
+        assert!(generated == committed, "include/dystonse_curves.h is outdated, regenerate it with `cbindgen --config cbindgen.toml --output include/dystonse_curves.h` in dystonse-curves-capi");
+    }
+}
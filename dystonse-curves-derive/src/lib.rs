@@ -0,0 +1,191 @@
+//! Derive macro for `dystonse_curves::tree::TreeData`. Use it through the `derive` 
+//! feature of `dystonse-curves` instead of depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Lit, Meta, NestedMeta, Attribute, Error};
+
+/// Derives `TreeData` (and an empty `LeafData`, unless `#[tree(no_leaf_data)]` is given) for a
+/// struct with named fields.
+/// 
+/// The struct is saved as a directory named after its `own_name`, and each field
+/// is saved within that directory under the field's name:
+/// 
+///  * by default, the field's type must implement `TreeData` and is saved with `save_tree`,
+///  * with `#[tree(leaf)]`, the field's type must implement `NodeData` and is saved into a single file,
+///  * with `#[tree(skip)]`, the field is not saved, and set to `Default::default()` when loading,
+///  * with `#[tree(rename = "...")]`, another name is used instead of the field's name.
+/// 
+/// On the struct itself, `#[tree(name = "...")]` sets `TreeData::NAME` (which defaults to 
+/// the struct's name) and `#[tree(collapsible)]` allows the whole struct to be saved into 
+/// a single file if its name is among the leaves. This requires the struct to implement 
+/// `Serialize` and `Deserialize`. Without `collapsible`, saving or loading the struct with its
+/// name among the leaves fails, as it could only be saved as a directory, which would then be
+/// looked up as a file. `#[tree(no_leaf_data)]` leaves out the empty `LeafData` implementation,
+/// so that the struct can implement it itself, e.g. to override `LeafData::VERSION` and `migrate`.
+#[proc_macro_derive(TreeData, attributes(tree))]
+pub fn derive_tree_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into()
+    }
+}
+
+/// How a single field is saved and loaded.
+enum FieldKind {
+    Tree,
+    Leaf,
+    Skip,
+}
+
+/// The options given in `#[tree(...)]` attributes.
+#[derive(Default)]
+struct Options {
+    name: Option<String>,
+    collapsible: bool,
+    no_leaf_data: bool,
+    leaf: bool,
+    skip: bool,
+}
+
+fn parse_options(attrs: &[Attribute]) -> syn::Result<Options> {
+    let mut options = Options::default();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("tree")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new_spanned(meta, "expected #[tree(...)]"))
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("collapsible") => options.collapsible = true,
+                NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("no_leaf_data") => options.no_leaf_data = true,
+                NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("leaf") => options.leaf = true,
+                NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("skip") => options.skip = true,
+                NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.path.is_ident("name") || nv.path.is_ident("rename") => {
+                    match &nv.lit {
+                        Lit::Str(s) => options.name = Some(s.value()),
+                        lit => return Err(Error::new_spanned(lit, "expected a string"))
+                    }
+                },
+                other => return Err(Error::new_spanned(other, "unknown tree attribute"))
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let struct_options = parse_options(&input.attrs)?;
+    let name = struct_options.name.clone().unwrap_or_else(|| ident.to_string());
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(ident, "TreeData can only be derived for structs with named fields"))
+        },
+        _ => return Err(Error::new_spanned(ident, "TreeData can only be derived for structs"))
+    };
+
+    let krate = quote!(::dystonse_curves);
+    let mut generics = input.generics.clone();
+    let mut saves = Vec::new();
+    let mut loads = Vec::new();
+
+    for field in fields {
+        let field_ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let options = parse_options(&field.attrs)?;
+        let kind = match (options.leaf, options.skip) {
+            (true, true) => return Err(Error::new_spanned(field_ident, "a field can't be both leaf and skip")),
+            (true, false) => FieldKind::Leaf,
+            (false, true) => FieldKind::Skip,
+            (false, false) => FieldKind::Tree,
+        };
+        let field_name = options.name.unwrap_or_else(|| field_ident.to_string());
+
+        match kind {
+            FieldKind::Tree => {
+                generics.make_where_clause().predicates.push(parse_quote!(#ty: #krate::tree::TreeData));
+                saves.push(quote! {
+                    #krate::tree::TreeData::save_tree_to_storage(&self.#field_ident, storage, &sub_dir_name, #field_name, format, leaves)?;
+                });
+                loads.push(quote! {
+                    #field_ident: <#ty as #krate::tree::TreeData>::load_tree_from_storage(storage, &sub_dir_name, #field_name, format, leaves)?,
+                });
+            },
+            FieldKind::Leaf => {
+                generics.make_where_clause().predicates.push(parse_quote!(#ty: #krate::tree::NodeData));
+                saves.push(quote! {
+                    #krate::tree::NodeData::save_to_storage(&self.#field_ident, storage, &sub_dir_name, &#krate::tree::encode_name(#field_name), format)?;
+                });
+                loads.push(quote! {
+                    #field_ident: *<#ty as #krate::tree::NodeData>::load_from_storage(storage, &sub_dir_name, &#krate::tree::encode_name(#field_name), format)?,
+                });
+            },
+            FieldKind::Skip => {
+                generics.make_where_clause().predicates.push(parse_quote!(#ty: ::std::default::Default));
+                loads.push(quote! {
+                    #field_ident: ::std::default::Default::default(),
+                });
+            }
+        }
+    }
+
+    let (collapse_save, collapse_load) = if struct_options.collapsible {
+        generics.make_where_clause().predicates.push(parse_quote!(Self: #krate::tree::NodeData));
+        (quote! {
+            if leaves.contains(&<Self as #krate::tree::TreeData>::NAME) {
+                return #krate::tree::NodeData::save_to_storage(self, storage, dir_name, &#krate::tree::encode_name(own_name), format);
+            }
+        }, quote! {
+            if leaves.contains(&<Self as #krate::tree::TreeData>::NAME) {
+                return Ok(*<Self as #krate::tree::NodeData>::load_from_storage(storage, dir_name, &#krate::tree::encode_name(own_name), format)?);
+            }
+        })
+    } else {
+        let not_collapsible = quote! {
+            if leaves.contains(&<Self as #krate::tree::TreeData>::NAME) {
+                return Err(format!("{} is among the leaves, but it can't be saved into a single file without #[tree(collapsible)]", <Self as #krate::tree::TreeData>::NAME).into());
+            }
+        };
+        (not_collapsible.clone(), not_collapsible)
+    };
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let (leaf_impl_generics, leaf_ty_generics, leaf_where_clause) = input.generics.split_for_impl();
+    let leaf_impl = if struct_options.no_leaf_data {
+        quote!()
+    } else {
+        quote! {
+            impl #leaf_impl_generics #krate::tree::LeafData for #ident #leaf_ty_generics #leaf_where_clause {}
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #krate::tree::TreeData for #ident #ty_generics #where_clause {
+            const NAME : &'static str = #name;
+
+            fn save_tree_to_storage(&self, storage: &dyn #krate::storage::Storage, dir_name: &str, own_name: &str, format: &#krate::tree::SerdeFormat, leaves: &Vec<&str>) -> #krate::FnResult<()> {
+                #collapse_save
+                #[allow(unused_variables)]
+                let sub_dir_name = format!("{}/{}", dir_name, #krate::tree::encode_name(own_name));
+                #(#saves)*
+                Ok(())
+            }
+
+            fn load_tree_from_storage(storage: &dyn #krate::storage::Storage, dir_name: &str, own_name: &str, format: &#krate::tree::SerdeFormat, leaves: &Vec<&str>) -> #krate::FnResult<Self> {
+                #collapse_load
+                #[allow(unused_variables)]
+                let sub_dir_name = format!("{}/{}", dir_name, #krate::tree::encode_name(own_name));
+                Ok(Self {
+                    #(#loads)*
+                })
+            }
+        }
+
+        #leaf_impl
+    })
+}
@@ -0,0 +1,280 @@
+//! Python bindings for dystonse-curves, built with maturin (see pyproject.toml). All
+//! curves use f32 for their x and y values, and all errors are raised as `ValueError`.
+
+use curves::{Curve, IrregularDynamicCurve, RegularDynamicCurve, Tup, weighted_average, distance};
+use curves::curve_set::CurveSet;
+use numpy::{AllowTypeChange, PyArray1, PyArrayLike1};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use simple_error::SimpleError;
+
+fn value_error(e: SimpleError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn check_y(y: f32) -> PyResult<()> {
+    if !(0.0..=1.0).contains(&y) {
+        return Err(PyValueError::new_err(format!("y must be between 0 and 1, but is {}", y)));
+    }
+    Ok(())
+}
+
+fn to_numpy<'py>(py: Python<'py>, (x, y): (Vec<f32>, Vec<f32>)) -> (Bound<'py, PyArray1<f32>>, Bound<'py, PyArray1<f32>>) {
+    (PyArray1::from_vec(py, x), PyArray1::from_vec(py, y))
+}
+
+/// A curve with points at arbitrary x values.
+#[pyclass(name = "IrregularDynamicCurve", module = "dystonse_curves")]
+#[derive(Clone)]
+pub struct PyIrregularCurve {
+    curve: IrregularDynamicCurve<f32, f32>
+}
+
+#[pymethods]
+impl PyIrregularCurve {
+    /// Create a curve from arrays of x and y values, which must be cumulative
+    /// probabilities from 0 to 1.
+    #[new]
+    fn new(x: PyArrayLike1<'_, f32, AllowTypeChange>, y: PyArrayLike1<'_, f32, AllowTypeChange>) -> PyResult<Self> {
+        let (x, y) = (x.as_array(), y.as_array());
+        if x.len() != y.len() {
+            return Err(PyValueError::new_err(format!("Got {} x values, but {} y values", x.len(), y.len())));
+        }
+        let points = x.iter().zip(y.iter()).map(|(x, y)| Tup { x: *x, y: *y }).collect();
+        let curve = IrregularDynamicCurve::try_new(points).map_err(value_error)?;
+        Ok(Self { curve })
+    }
+
+    #[staticmethod]
+    fn deserialize_compact(bytes: &[u8]) -> PyResult<Self> {
+        let curve = IrregularDynamicCurve::try_deserialize_compact(bytes).map_err(value_error)?;
+        Ok(Self { curve })
+    }
+
+    fn serialize_compact<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.curve.serialize_compact())
+    }
+
+    fn serialize_compact_limited<'py>(&self, py: Python<'py>, max_bytes: usize) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.curve.serialize_compact_limited(max_bytes))
+    }
+
+    fn min_x(&self) -> f32 {
+        self.curve.min_x()
+    }
+
+    fn max_x(&self) -> f32 {
+        self.curve.max_x()
+    }
+
+    fn y_at_x(&self, x: f32) -> f32 {
+        self.curve.y_at_x(x)
+    }
+
+    fn x_at_y(&self, y: f32) -> PyResult<f32> {
+        check_y(y)?;
+        Ok(self.curve.x_at_y(y))
+    }
+
+    /// Returns the x and y values as a tuple of two numpy arrays.
+    fn get_values_as_vectors<'py>(&self, py: Python<'py>) -> (Bound<'py, PyArray1<f32>>, Bound<'py, PyArray1<f32>>) {
+        to_numpy(py, self.curve.get_values_as_vectors())
+    }
+
+    fn __len__(&self) -> usize {
+        self.curve.get_x_values().len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("IrregularDynamicCurve({} points from {} to {})", self.__len__(), self.min_x(), self.max_x())
+    }
+}
+
+/// A curve with y values at equally spaced x values.
+#[pyclass(name = "RegularDynamicCurve", module = "dystonse_curves")]
+#[derive(Clone)]
+pub struct PyRegularCurve {
+    curve: RegularDynamicCurve<f32, f32>
+}
+
+#[pymethods]
+impl PyRegularCurve {
+    /// Create a curve with the y values at x0, x0 + step, x0 + 2 * step, …
+    #[new]
+    fn new(step: f32, x0: f32, y: PyArrayLike1<'_, f32, AllowTypeChange>) -> PyResult<Self> {
+        let curve = RegularDynamicCurve::try_new(step, x0, y.as_array().to_vec()).map_err(value_error)?;
+        Ok(Self { curve })
+    }
+
+    #[staticmethod]
+    fn deserialize_compact(bytes: &[u8]) -> PyResult<Self> {
+        let curve = RegularDynamicCurve::try_deserialize_compact(bytes).map_err(value_error)?;
+        Ok(Self { curve })
+    }
+
+    fn serialize_compact<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.curve.serialize_compact())
+    }
+
+    fn serialize_compact_limited<'py>(&self, py: Python<'py>, max_bytes: usize) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.curve.serialize_compact_limited(max_bytes))
+    }
+
+    fn min_x(&self) -> f32 {
+        self.curve.min_x()
+    }
+
+    fn max_x(&self) -> f32 {
+        self.curve.max_x()
+    }
+
+    fn y_at_x(&self, x: f32) -> f32 {
+        self.curve.y_at_x(x)
+    }
+
+    fn x_at_y(&self, y: f32) -> PyResult<f32> {
+        check_y(y)?;
+        Ok(self.curve.x_at_y(y))
+    }
+
+    /// Returns the x and y values as a tuple of two numpy arrays.
+    fn get_values_as_vectors<'py>(&self, py: Python<'py>) -> (Bound<'py, PyArray1<f32>>, Bound<'py, PyArray1<f32>>) {
+        to_numpy(py, self.curve.get_values_as_vectors())
+    }
+
+    fn __len__(&self) -> usize {
+        self.curve.get_x_values().len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("RegularDynamicCurve({} points from {} to {})", self.__len__(), self.min_x(), self.max_x())
+    }
+}
+
+/// A set of irregular curves, each belonging to a key, between which curves
+/// can be interpolated.
+#[pyclass(name = "CurveSet", module = "dystonse_curves")]
+#[derive(Clone)]
+pub struct PyCurveSet {
+    set: CurveSet<f32, IrregularDynamicCurve<f32, f32>>
+}
+
+#[pymethods]
+impl PyCurveSet {
+    #[new]
+    fn new() -> Self {
+        Self { set: CurveSet::new() }
+    }
+
+    #[staticmethod]
+    fn deserialize_compact(bytes: &[u8]) -> PyResult<Self> {
+        let set = CurveSet::try_deserialize_compact(bytes, IrregularDynamicCurve::try_deserialize_compact).map_err(value_error)?;
+        Ok(Self { set })
+    }
+
+    fn serialize_compact<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.set.serialize_compact())
+    }
+
+    fn add_curve(&mut self, key: f32, curve: PyRef<'_, PyIrregularCurve>) -> PyResult<()> {
+        if !key.is_finite() {
+            return Err(PyValueError::new_err(format!("Invalid key {}", key)));
+        }
+        if self.set.curves.iter().any(|(k, _)| *k == key) {
+            return Err(PyValueError::new_err(format!("Duplicate key {}", key)));
+        }
+        self.set.add_curve(key, curve.curve.clone());
+        Ok(())
+    }
+
+    /// The keys, in ascending order.
+    fn keys<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        PyArray1::from_vec(py, self.set.curves.iter().map(|(k, _)| *k).collect())
+    }
+
+    /// The curve for the given key, interpolated between the two nearest curves.
+    /// Raises a `ValueError` if the key is not strictly between the smallest and largest key.
+    fn curve_at_x(&self, x: f32) -> PyResult<PyIrregularCurve> {
+        let curve = self.set.curve_at_x(x).map_err(value_error)?;
+        Ok(PyIrregularCurve { curve })
+    }
+
+    /// Like `curve_at_x`, but returns the first or last curve if the key is out of bounds.
+    fn curve_at_x_with_continuation(&self, x: f32) -> PyResult<PyIrregularCurve> {
+        if self.set.curves.is_empty() {
+            return Err(PyValueError::new_err("Empty curve set"));
+        }
+        Ok(PyIrregularCurve { curve: self.set.curve_at_x_with_continuation(x) })
+    }
+
+    fn min_x(&self) -> PyResult<f32> {
+        if self.set.curves.is_empty() {
+            return Err(PyValueError::new_err("Empty curve set"));
+        }
+        Ok(self.set.min_x())
+    }
+
+    fn max_x(&self) -> PyResult<f32> {
+        if self.set.curves.is_empty() {
+            return Err(PyValueError::new_err("Empty curve set"));
+        }
+        Ok(self.set.max_x())
+    }
+
+    fn __len__(&self) -> usize {
+        self.set.curves.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("CurveSet({} curves)", self.set.curves.len())
+    }
+}
+
+/// Either kind of curve, for the functions which accept both.
+#[derive(FromPyObject)]
+enum AnyCurve<'py> {
+    Irregular(PyRef<'py, PyIrregularCurve>),
+    Regular(PyRef<'py, PyRegularCurve>),
+}
+
+impl AnyCurve<'_> {
+    fn as_curve(&self) -> &dyn Curve {
+        match self {
+            AnyCurve::Irregular(c) => &c.curve,
+            AnyCurve::Regular(c) => &c.curve,
+        }
+    }
+}
+
+/// The weighted average of the curves, as an irregular curve.
+#[pyfunction]
+#[pyo3(name = "weighted_average")]
+fn py_weighted_average(curves: Vec<AnyCurve<'_>>, weights: Vec<f32>) -> PyResult<PyIrregularCurve> {
+    if curves.len() != weights.len() {
+        return Err(PyValueError::new_err(format!("Got {} curves, but {} weights", curves.len(), weights.len())));
+    }
+    if curves.is_empty() {
+        return Err(PyValueError::new_err("No curves given"));
+    }
+    let curves = curves.iter().map(|c| c.as_curve()).collect();
+    Ok(PyIrregularCurve { curve: weighted_average(curves, weights) })
+}
+
+/// The area between the two curves.
+#[pyfunction]
+#[pyo3(name = "distance")]
+fn py_distance(a: AnyCurve<'_>, b: AnyCurve<'_>) -> f32 {
+    distance(a.as_curve(), b.as_curve())
+}
+
+#[pymodule]
+#[pyo3(name = "dystonse_curves")]
+fn dystonse_curves_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyIrregularCurve>()?;
+    m.add_class::<PyRegularCurve>()?;
+    m.add_class::<PyCurveSet>()?;
+    m.add_function(wrap_pyfunction!(py_weighted_average, m)?)?;
+    m.add_function(wrap_pyfunction!(py_distance, m)?)?;
+    Ok(())
+}
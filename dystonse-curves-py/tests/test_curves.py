@@ -0,0 +1,76 @@
+# Run with `maturin develop && pytest` from the dystonse-curves-py directory.
+import numpy as np
+import pytest
+
+from dystonse_curves import CurveSet, IrregularDynamicCurve, RegularDynamicCurve, distance, weighted_average
+
+
+def test_irregular():
+    curve = IrregularDynamicCurve(np.array([0.0, 5.0, 10.0]), [0.0, 0.5, 1.0])
+    assert len(curve) == 3
+    assert curve.y_at_x(2.5) == pytest.approx(0.25)
+    assert curve.x_at_y(0.75) == pytest.approx(7.5)
+    x, y = curve.get_values_as_vectors()
+    assert x.dtype == np.float32
+    np.testing.assert_allclose(x, [0.0, 5.0, 10.0])
+
+    loaded = IrregularDynamicCurve.deserialize_compact(curve.serialize_compact())
+    assert loaded.min_x() == 0.0
+    assert loaded.max_x() == 10.0
+    # curves with more points than the format holds, or limits below its header, are simplified
+    many = IrregularDynamicCurve(np.arange(300.0), np.linspace(0.0, 1.0, 300) ** 2)
+    assert IrregularDynamicCurve.deserialize_compact(many.serialize_compact()).max_x() == 299.0
+    assert IrregularDynamicCurve.deserialize_compact(many.serialize_compact_limited(0)).max_x() == 299.0
+
+    with pytest.raises(ValueError):
+        IrregularDynamicCurve([0.0, 5.0, 10.0], [0.0, 0.7, 0.5])
+    with pytest.raises(ValueError):
+        IrregularDynamicCurve.deserialize_compact(b"\x01")
+    with pytest.raises(ValueError):
+        curve.x_at_y(1.5)
+
+
+def test_regular():
+    curve = RegularDynamicCurve(10.0, 2.0, np.array([0.0, 0.5, 1.0]))
+    assert curve.min_x() == 2.0
+    assert curve.max_x() == 22.0
+    assert curve.y_at_x(7.0) == pytest.approx(0.25)
+    with pytest.raises(ValueError):
+        RegularDynamicCurve(0.0, 2.0, [0.0, 1.0])
+
+    loaded = RegularDynamicCurve.deserialize_compact(curve.serialize_compact())
+    assert loaded.min_x() == 2.0
+    assert loaded.max_x() == 22.0
+    assert len(RegularDynamicCurve.deserialize_compact(curve.serialize_compact_limited(13))) == 2
+    with pytest.raises(ValueError):
+        RegularDynamicCurve.deserialize_compact(b"\x02")
+
+
+def test_average_and_distance():
+    a = IrregularDynamicCurve([0.0, 10.0], [0.0, 1.0])
+    b = RegularDynamicCurve(10.0, 10.0, [0.0, 1.0])
+    average = weighted_average([a, b], [0.5, 0.5])
+    assert average.x_at_y(0.5) == pytest.approx(10.0)
+    assert distance(a, b) == pytest.approx(10.0)
+    with pytest.raises(ValueError):
+        weighted_average([a, b], [1.0])
+
+
+def test_curve_set():
+    curves = CurveSet()
+    curves.add_curve(0.0, IrregularDynamicCurve([0.0, 10.0], [0.0, 1.0]))
+    curves.add_curve(60.0, IrregularDynamicCurve([60.0, 70.0], [0.0, 1.0]))
+    assert len(curves) == 2
+    np.testing.assert_allclose(curves.keys(), [0.0, 60.0])
+    assert curves.curve_at_x(30.0).min_x() == pytest.approx(30.0)
+    with pytest.raises(ValueError):
+        curves.curve_at_x(90.0)
+    assert curves.curve_at_x_with_continuation(90.0).min_x() == pytest.approx(60.0)
+    with pytest.raises(ValueError):
+        curves.add_curve(0.0, IrregularDynamicCurve([0.0, 1.0], [0.0, 1.0]))
+
+    loaded = CurveSet.deserialize_compact(curves.serialize_compact())
+    np.testing.assert_allclose(loaded.keys(), [0.0, 60.0])
+    assert loaded.max_x() == 60.0
+    with pytest.raises(ValueError):
+        CurveSet.deserialize_compact(b"\x03")
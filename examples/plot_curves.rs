@@ -1,7 +1,7 @@
 use dystonse_curves::regular_dynamic::RegularDynamicCurve;
 use dystonse_curves::irregular_dynamic::*;
 use dystonse_curves::{Curve, weighted_average};
-use gnuplot::{Figure};
+use dystonse_curves::plot::Plot;
     
 fn main() {
     //comment out whatever you don't want to test.
@@ -11,7 +11,7 @@ fn main() {
     test_weighted_average(); //two curves and a weighted average between them
 }
 
-// this plots a simple example curve with gnuplot (should open a new gnuplot window automatically)
+// this plots a simple example curve with gnuplot into plot.svg
 fn test_plot() {
 
     let c = RegularDynamicCurve::<f32, f32>::new(
@@ -20,10 +20,13 @@ fn test_plot() {
         vec!{0.0, 0.6, 1.0}
     );
 
-    c.plot_curve_with_gnuplot();
+    match c.plot_curve_with_gnuplot("plot.svg") {
+        Ok(_) => {},
+        Err(e) => {println!("Error: {}", e);}
+    }
 }
 
-// this plots three curves (two regular, one irregular) in one plot
+// this plots three curves (two regular, one irregular) in one plot into multi_curve.svg
 fn test_multi_curve() {
     let c = RegularDynamicCurve::<f32, f32>::new(
         10.0,
@@ -54,10 +57,10 @@ fn test_multi_curve() {
         Box::new(&e), 
     };
 
-    multi_curve_plot(v);
+    multi_curve_plot(v, "multi_curve.svg");
 }
 
-// this plots two curves and their weighted average
+// this plots two curves and their weighted average into weighted_average.svg
 fn test_weighted_average() {
 
     let d = RegularDynamicCurve::<f32, f32>::new(
@@ -85,20 +88,17 @@ fn test_weighted_average() {
         Box::new(&df), 
     };
 
-    multi_curve_plot(v);
+    multi_curve_plot(v, "weighted_average.svg");
 }
 
-fn multi_curve_plot(curves: Vec<Box<&dyn Curve>>) {
-    let mut fg = Figure::new();
-    let axes = fg.axes2d();
-    for c in curves {
-        let vecs = c.get_values_as_vectors();
-        let x = vecs.0;
-        let y = vecs.1;
-        axes.lines_points(&x, &y, &[]);
+fn multi_curve_plot(curves: Vec<Box<&dyn Curve>>, path: &str) {
+    let mut plot = Plot::new();
+    for (i, c) in curves.iter().enumerate() {
+        plot.add_curve(**c, &format!("Curve {}", i + 1));
     }
-    match fg.show() {
+    plot.with_default_quantile_markers();
+    match plot.save(path) {
         Ok(_) => {},
         Err(e) => {println!("Error: {}", e);}
     }
-}
\ No newline at end of file
+}
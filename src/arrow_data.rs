@@ -0,0 +1,275 @@
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, AsArray, Float32Array, Float32Builder, ListBuilder, StringArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Float32Type, Schema};
+use arrow::record_batch::RecordBatch;
+
+use crate::{Curve, FnResult, QUANTILES};
+use crate::conversion::LikeANumber;
+use crate::curve_set::CurveSet;
+use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+use crate::lazy_tree::{LazyTree, MemorySize};
+use crate::tree::{NodeData, LeafData};
+use simple_error::bail;
+
+/// Convert curves into a record batch with the columns `path` (the name of each curve),
+/// `x` and `y` (lists of the coordinates of its points), and one column for each of the
+/// `QUANTILES`, which are ignored when importing. All numbers are converted to f32.
+pub fn to_record_batch<'a, C, I>(curves: I) -> FnResult<RecordBatch> where
+C: Curve + ?Sized + 'a,
+I: IntoIterator<Item = (String, &'a C)>
+{
+    let mut paths = Vec::new();
+    let mut xs = ListBuilder::new(Float32Builder::new());
+    let mut ys = ListBuilder::new(Float32Builder::new());
+    let mut quantiles = vec![Vec::new(); QUANTILES.len()];
+    for (path, curve) in curves {
+        let (x, y) = curve.get_values_as_vectors();
+        xs.values().append_slice(&x);
+        xs.append(true);
+        ys.values().append_slice(&y);
+        ys.append(true);
+        for (column, (_, q)) in quantiles.iter_mut().zip(QUANTILES.iter()) {
+            column.push(curve.x_at_y(*q));
+        }
+        paths.push(path);
+    }
+
+    let mut columns : Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(paths)),
+        Arc::new(xs.finish()),
+        Arc::new(ys.finish()),
+    ];
+    columns.extend(quantiles.into_iter().map(|column| Arc::new(Float32Array::from(column)) as ArrayRef));
+    let mut fields = vec![
+        Field::new("path", DataType::Utf8, false),
+        Field::new("x", columns[1].data_type().clone(), false),
+        Field::new("y", columns[2].data_type().clone(), false),
+    ];
+    fields.extend(QUANTILES.iter().map(|(name, _)| Field::new(*name, DataType::Float32, false)));
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?)
+}
+
+/// Convert a record batch with the columns `path`, `x` and `y` (as written by
+/// `to_record_batch`) back into curves, which are validated like deserialized curves.
+/// The lists may contain other numbers than f32, e.g. the f64 values which pandas, polars
+/// and DuckDB write by default, which are converted to f32.
+pub fn from_record_batch(batch: &RecordBatch) -> FnResult<Vec<(String, IrregularDynamicCurve<f32, f32>)>> {
+    let column = |name: &str| match batch.column_by_name(name) {
+        Some(column) if column.null_count() == 0 => Ok(column),
+        Some(_) => Err(format!("Column {} contains null values", name)),
+        None => Err(format!("Missing column {}", name))
+    };
+    let paths = column("path")?.as_string_opt::<i32>().ok_or("Column path does not contain strings")?;
+    let xs = column("x")?.as_list_opt::<i32>().ok_or("Column x does not contain lists")?;
+    let ys = column("y")?.as_list_opt::<i32>().ok_or("Column y does not contain lists")?;
+
+    let mut curves = Vec::with_capacity(batch.num_rows());
+    for i in 0..batch.num_rows() {
+        let path = paths.value(i);
+        let to_f32 = |values: ArrayRef| -> FnResult<ArrayRef> {
+            if !values.data_type().is_numeric() {
+                bail!("The points of {} are not numbers, but {}", path, values.data_type());
+            }
+            Ok(cast(&values, &DataType::Float32)?)
+        };
+        let (x, y) = (to_f32(xs.value(i))?, to_f32(ys.value(i))?);
+        let (x, y) = match (x.as_primitive_opt::<Float32Type>(), y.as_primitive_opt::<Float32Type>()) {
+            (Some(x), Some(y)) if x.null_count() == 0 && y.null_count() == 0 => (x, y),
+            _ => bail!("The points of {} are not non-null f32 values", path)
+        };
+        if x.len() != y.len() {
+            bail!("The curve {} has {} x values, but {} y values", path, x.len(), y.len());
+        }
+        let points = x.values().iter().zip(y.values().iter()).map(|(x, y)| Tup { x: *x, y: *y }).collect();
+        let curve = IrregularDynamicCurve::from_external(points).map_err(|why| format!("Invalid curve {}: {}", path, why))?;
+        curves.push((path.to_string(), curve));
+    }
+    Ok(curves)
+}
+
+/// Write the curves into an Arrow IPC file, see `to_record_batch`.
+pub fn write_ipc<'a, C, I>(curves: I, writer: impl Write) -> FnResult<()> where
+C: Curve + ?Sized + 'a,
+I: IntoIterator<Item = (String, &'a C)>
+{
+    let batch = to_record_batch(curves)?;
+    let mut ipc = arrow::ipc::writer::FileWriter::try_new(writer, &batch.schema())?;
+    ipc.write(&batch)?;
+    ipc.finish()?;
+    Ok(())
+}
+
+/// Read the curves from an Arrow IPC file, see `from_record_batch`.
+pub fn read_ipc(reader: impl Read + Seek) -> FnResult<Vec<(String, IrregularDynamicCurve<f32, f32>)>> {
+    let mut curves = Vec::new();
+    for batch in arrow::ipc::reader::FileReader::try_new(reader, None)? {
+        curves.extend(from_record_batch(&batch?)?);
+    }
+    Ok(curves)
+}
+
+/// Write the curves into a Parquet file, see `to_record_batch`.
+#[cfg(feature = "parquet")]
+pub fn write_parquet<'a, C, I>(curves: I, writer: impl Write + Send) -> FnResult<()> where
+C: Curve + ?Sized + 'a,
+I: IntoIterator<Item = (String, &'a C)>
+{
+    let batch = to_record_batch(curves)?;
+    let mut parquet = parquet::arrow::ArrowWriter::try_new(writer, batch.schema(), None)?;
+    parquet.write(&batch)?;
+    parquet.close()?;
+    Ok(())
+}
+
+/// Read the curves from a Parquet file, see `from_record_batch`.
+#[cfg(feature = "parquet")]
+pub fn read_parquet(file: std::fs::File) -> FnResult<Vec<(String, IrregularDynamicCurve<f32, f32>)>> {
+    let mut curves = Vec::new();
+    for batch in parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)?.build()? {
+        curves.extend(from_record_batch(&batch?)?);
+    }
+    Ok(curves)
+}
+
+/// The curves of a curve set, named by their keys (as f32).
+pub fn curve_set_rows<T, C>(set: &CurveSet<T, C>) -> impl Iterator<Item = (String, &C)> where
+T: LikeANumber,
+C: Curve + NodeData
+{
+    set.curves.iter().map(|(key, curve)| (key.make_into_f32().to_string(), curve))
+}
+
+/// All curves of a saved tree, named by their paths joined with `/`, and sorted by those names.
+/// For a tree of curve sets, use the curve type as L and save the tree without collapsing the
+/// curve sets, so that the names end with the file names of the curves, e.g. `Linie 4/Brill/curve_60`.
+pub fn lazy_tree_rows<L>(tree: &LazyTree<L>) -> FnResult<Vec<(String, Arc<L>)>> where
+L: Curve + NodeData + LeafData + MemorySize
+{
+    let mut paths : Vec<&Vec<String>> = tree.paths().collect();
+    paths.sort();
+    let mut rows = Vec::with_capacity(paths.len());
+    for path in paths {
+        let parts : Vec<&str> = path.iter().map(|p| p.as_str()).collect();
+        if let Some(curve) = tree.get(&parts)? {
+            rows.push((path.join("/"), curve));
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use crate::arrow_data::*;
+    use crate::tree::{TreeData, SerdeFormat};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    type C = IrregularDynamicCurve<f32, f32>;
+
+    fn test_set(offset: f32) -> CurveSet<f32, C> {
+        let mut set = CurveSet::new();
+        for key in &[0.0, 60.0] {
+            set.add_curve(*key, C::new(vec![
+                Tup { x: offset + key, y: 0.0 },
+                Tup { x: offset + key + 50.0, y: 0.5 },
+                Tup { x: offset + key + 100.0, y: 1.0 },
+            ]));
+        }
+        set
+    }
+
+    #[test]
+    fn test_arrow() {
+        let set = test_set(0.0);
+        let batch = to_record_batch(curve_set_rows(&set)).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema().field(0).name(), "path");
+        let medians = batch.column_by_name("q50").unwrap().as_primitive::<Float32Type>();
+        assert_eq!(medians.values().to_vec(), vec![50.0, 110.0]);
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_ipc(curve_set_rows(&set), &mut buffer).unwrap();
+        buffer.set_position(0);
+        let curves = read_ipc(buffer).unwrap();
+        assert_eq!(curves.len(), 2);
+        assert_eq!(curves[1].0, "60");
+        assert_eq!(curves[1].1.get_values_as_vectors(), set.curves[1].1.get_values_as_vectors());
+
+        // invalid curves are rejected on import, e.g. if x and y have been mixed up
+        let broken = C::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 5.0, y: 0.2 }, Tup { x: 10.0, y: 1.0 }]);
+        let mut batch = to_record_batch(vec![("broken".to_string(), &broken)]).unwrap();
+        let mut columns = batch.columns().to_vec();
+        columns.swap(1, 2);
+        batch = RecordBatch::try_new(batch.schema(), columns).unwrap();
+        assert!(from_record_batch(&batch).unwrap_err().to_string().contains("Invalid curve broken"));
+    }
+
+    #[test]
+    fn test_arrow_f64() {
+        use arrow::array::{Float64Builder, StringBuilder};
+
+        // a batch like the ones written by pandas, with f64 values and without the quantiles
+        let mut xs = ListBuilder::new(Float64Builder::new());
+        xs.values().append_slice(&[0.0, 50.0, 100.0]);
+        xs.append(true);
+        let mut ys = ListBuilder::new(Float64Builder::new());
+        ys.values().append_slice(&[0.0, 0.5, 1.0]);
+        ys.append(true);
+        let batch = RecordBatch::try_from_iter(vec![
+            ("path", Arc::new(StringArray::from(vec!["curve"])) as ArrayRef),
+            ("x", Arc::new(xs.finish()) as ArrayRef),
+            ("y", Arc::new(ys.finish()) as ArrayRef),
+        ]).unwrap();
+        let curves = from_record_batch(&batch).unwrap();
+        assert_eq!(curves[0].1.get_values_as_vectors(), (vec![0.0, 50.0, 100.0], vec![0.0, 0.5, 1.0]));
+
+        // values which are no numbers can't be converted
+        let mut texts = ListBuilder::new(StringBuilder::new());
+        texts.values().append_value("zero");
+        texts.values().append_value("one");
+        texts.append(true);
+        let batch = RecordBatch::try_from_iter(vec![
+            ("path", Arc::new(StringArray::from(vec!["curve"])) as ArrayRef),
+            ("x", Arc::new(texts.finish()) as ArrayRef),
+            ("y", batch.column(2).clone()),
+        ]).unwrap();
+        assert!(from_record_batch(&batch).unwrap_err().to_string().contains("are not numbers"));
+    }
+
+    #[test]
+    fn test_arrow_tree() {
+        let mut routes = HashMap::new();
+        routes.insert("Linie 4".to_string(), test_set(0.0));
+        routes.insert("Linie 6".to_string(), test_set(1000.0));
+        let dir = std::env::temp_dir().join("dystonse-curves-test_arrow_tree");
+        let dir_name = dir.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        routes.save_tree(dir_name, "routes", &SerdeFormat::MessagePack, &vec![]).unwrap();
+
+        let tree = LazyTree::<C>::open(dir_name, "routes", SerdeFormat::MessagePack, 0).unwrap();
+        let rows = lazy_tree_rows(&tree).unwrap();
+        let names : Vec<&str> = rows.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["Linie 4/curve_0", "Linie 4/curve_60", "Linie 6/curve_0", "Linie 6/curve_60"]);
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_ipc(rows.iter().map(|(name, curve)| (name.clone(), curve.as_ref())), &mut buffer).unwrap();
+        buffer.set_position(0);
+        let curves = read_ipc(buffer).unwrap();
+        assert_eq!(curves[3].1.min_x(), 1060.0);
+
+        #[cfg(feature = "parquet")]
+        {
+            let path = dir.join("routes.parquet");
+            write_parquet(rows.iter().map(|(name, curve)| (name.clone(), curve.as_ref())), std::fs::File::create(&path).unwrap()).unwrap();
+            let curves = read_parquet(std::fs::File::open(&path).unwrap()).unwrap();
+            assert_eq!(curves.len(), 4);
+            assert_eq!(curves[2].0, "Linie 6/curve_0");
+            assert_eq!(curves[2].1.min_x(), 1000.0);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
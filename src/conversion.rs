@@ -1,9 +1,9 @@
 use fixed::types::{U1F7, U1F15};
 use fixed::traits::{LossyFrom};
 use half::prelude::*;
-use std::ops::{Add, Sub, Mul, Div};
-use std::cmp::{PartialOrd};
-use std::fmt::Debug;
+use core::ops::{Add, Sub, Mul, Div};
+use core::cmp::{PartialOrd};
+use core::fmt::Debug;
 
 pub trait LikeANumber: 
     ConvertF32 + 
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+
+use crate::FnResult;
+use crate::conversion::LikeANumber;
+use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+use crate::regular_dynamic::RegularDynamicCurve;
+use crate::curve_set::CurveSet;
+use crate::tree::NodeData;
+use simple_error::bail;
+
+/// Import and export as CSV, for use in spreadsheets and other tools. All numbers are
+/// written as f32. When importing, the first row is treated as a header and ignored,
+/// and the curves are validated like all deserialized curves (or repaired, within
+/// `repair::with_repair`).
+pub trait CsvData: Sized {
+    fn write_csv(&self, writer: impl Write) -> FnResult<()>;
+    fn read_csv(reader: impl Read) -> FnResult<Self>;
+}
+
+/// One row per point, with the columns `x,y`.
+impl<X, Y> CsvData for IrregularDynamicCurve<X, Y> where
+X: LikeANumber,
+Y: LikeANumber
+{
+    fn write_csv(&self, writer: impl Write) -> FnResult<()> {
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(["x", "y"])?;
+        for p in &self.points {
+            csv.serialize((p.x.make_into_f32(), p.y.make_into_f32()))?;
+        }
+        csv.flush()?;
+        Ok(())
+    }
+
+    fn read_csv(reader: impl Read) -> FnResult<Self> {
+        let mut points = Vec::new();
+        for row in csv::Reader::from_reader(reader).deserialize() {
+            let (x, y) : (f32, f32) = row?;
+            points.push(Tup { x: X::make_from_f32(x), y: Y::make_from_f32(y) });
+        }
+        Ok(Self::from_external(points)?)
+    }
+}
+
+/// A header row `x0,step` followed by a row with their values, and then another header
+/// row `y` followed by one row per y value.
+impl<X, Y> CsvData for RegularDynamicCurve<X, Y> where
+X: LikeANumber,
+Y: LikeANumber
+{
+    fn write_csv(&self, writer: impl Write) -> FnResult<()> {
+        let mut csv = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+        csv.write_record(["x0", "step"])?;
+        csv.serialize((self.x0.make_into_f32(), self.s.make_into_f32()))?;
+        csv.write_record(["y"])?;
+        for y in &self.y {
+            csv.serialize((y.make_into_f32(),))?;
+        }
+        csv.flush()?;
+        Ok(())
+    }
+
+    fn read_csv(reader: impl Read) -> FnResult<Self> {
+        let mut csv = csv::ReaderBuilder::new().flexible(true).has_headers(false).from_reader(reader);
+        let mut rows = csv.records();
+        let mut next_row = || -> FnResult<Option<csv::StringRecord>> { Ok(rows.next().transpose()?) };
+
+        next_row()?;
+        let (x0, s) : (f32, f32) = match next_row()? {
+            Some(row) => row.deserialize(None)?,
+            None => bail!("Missing row with x0 and step")
+        };
+        if next_row()?.is_none() {
+            bail!("Missing y values");
+        }
+        let mut y = Vec::new();
+        while let Some(row) = next_row()? {
+            let (value,) : (f32,) = row.deserialize(None)?;
+            y.push(Y::make_from_f32(value));
+        }
+        Ok(Self::from_external(X::make_from_f32(s), X::make_from_f32(x0), y)?)
+    }
+}
+
+/// The long format with the columns `key,x,y`, and one row for each point of each curve.
+/// When importing, the rows of each key don't need to be adjacent.
+impl<T, X, Y> CsvData for CurveSet<T, IrregularDynamicCurve<X, Y>> where
+T: LikeANumber,
+X: LikeANumber,
+Y: LikeANumber,
+IrregularDynamicCurve<X, Y>: NodeData
+{
+    fn write_csv(&self, writer: impl Write) -> FnResult<()> {
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(["key", "x", "y"])?;
+        for (key, curve) in &self.curves {
+            for p in &curve.points {
+                csv.serialize((key.make_into_f32(), p.x.make_into_f32(), p.y.make_into_f32()))?;
+            }
+        }
+        csv.flush()?;
+        Ok(())
+    }
+
+    fn read_csv(reader: impl Read) -> FnResult<Self> {
+        // the points of each key, in the order in which the keys first appear
+        let mut groups : Vec<(f32, Vec<Tup<X, Y>>)> = Vec::new();
+        // maps the bits of each key to its position in groups
+        let mut positions : HashMap<u32, usize> = HashMap::new();
+        for row in csv::Reader::from_reader(reader).deserialize() {
+            let (key, x, y) : (f32, f32, f32) = row?;
+            if !key.is_finite() {
+                bail!("Invalid key {}", key);
+            }
+            let point = Tup { x: X::make_from_f32(x), y: Y::make_from_f32(y) };
+            // adding 0.0 turns -0.0 into 0.0, which compare as equal, but have different bits
+            let position = *positions.entry((key + 0.0).to_bits()).or_insert_with(|| {
+                groups.push((key, Vec::new()));
+                groups.len() - 1
+            });
+            groups[position].1.push(point);
+        }
+
+        let mut set = Self::new();
+        // maps the bits of each converted key to the key from the file
+        let mut converted_keys : HashMap<u32, f32> = HashMap::new();
+        for (key, points) in groups {
+            let converted = T::make_from_f32(key);
+            if let Some(other) = converted_keys.insert((converted.make_into_f32() + 0.0).to_bits(), key) {
+                bail!("Keys {} and {} are the same when converted to {}", other, key, std::any::type_name::<T>());
+            }
+            let curve = IrregularDynamicCurve::from_external(points).map_err(|why| format!("Invalid curve for key {}: {}", key, why))?;
+            set.add_curve(converted, curve);
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csv_data::CsvData;
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+    use crate::regular_dynamic::RegularDynamicCurve;
+    use crate::curve_set::CurveSet;
+    use crate::repair::with_repair;
+    use crate::Curve;
+
+    type C = IrregularDynamicCurve<f32, f32>;
+
+    #[test]
+    fn test_csv() {
+        let curve = C::new(vec![
+            Tup { x: 0.0, y: 0.0 },
+            Tup { x: 5.5, y: 0.25 },
+            Tup { x: 10.0, y: 1.0 },
+        ]);
+        let mut buffer = Vec::new();
+        curve.write_csv(&mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer.clone()).unwrap(), "x,y\n0.0,0.0\n5.5,0.25\n10.0,1.0\n");
+        assert_eq!(C::read_csv(&buffer[..]).unwrap().get_values_as_vectors(), curve.get_values_as_vectors());
+
+        let regular = RegularDynamicCurve::<f32, f32>::new(10.0, 2.0, vec![0.0, 0.5, 1.0]);
+        let mut buffer = Vec::new();
+        regular.write_csv(&mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer.clone()).unwrap(), "x0,step\n2.0,10.0\ny\n0.0\n0.5\n1.0\n");
+        let loaded = RegularDynamicCurve::<f32, f32>::read_csv(&buffer[..]).unwrap();
+        assert_eq!(loaded.get_values_as_vectors(), regular.get_values_as_vectors());
+
+        let mut set = CurveSet::<f32, C>::new();
+        set.add_curve(0.0, curve.clone());
+        set.add_curve(60.0, C::new(vec![Tup { x: 1.0, y: 0.0 }, Tup { x: 2.0, y: 1.0 }]));
+        let mut buffer = Vec::new();
+        set.write_csv(&mut buffer).unwrap();
+        let loaded = CurveSet::<f32, C>::read_csv(&buffer[..]).unwrap();
+        assert_eq!(loaded.curves.len(), 2);
+        assert_eq!(loaded.curves[1].0, 60.0);
+        assert_eq!(loaded.curves[1].1.get_x_values(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_csv_validation() {
+        let unsorted = "x,y\n10,1\n0,0\n";
+        assert!(C::read_csv(unsorted.as_bytes()).unwrap_err().to_string().contains("Unsorted x values"));
+        assert_eq!(with_repair(|| C::read_csv(unsorted.as_bytes())).unwrap().get_x_values(), vec![0.0, 10.0]);
+        assert!(C::read_csv("x,y\n0,zero\n10,1\n".as_bytes()).is_err());
+
+        assert!(RegularDynamicCurve::<f32, f32>::read_csv("x0,step\n0,10\ny\n0\n0.6\n0.5\n1\n".as_bytes()).is_err());
+        assert!(RegularDynamicCurve::<f32, f32>::read_csv("x0,step\n0,10\n".as_bytes()).is_err());
+
+        // the rows of a key may be spread over the file
+        let set = CurveSet::<f32, C>::read_csv("key,x,y\n5,0,0\n0,3,0\n5,10,1\n0,4,1\n".as_bytes()).unwrap();
+        assert_eq!(set.curves.len(), 2);
+        assert_eq!(set.curves[0].1.get_x_values(), vec![3.0, 4.0]);
+        let error = CurveSet::<f32, C>::read_csv("key,x,y\n5,0,0\n5,10,0.5\n".as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("Invalid curve for key 5"), "{}", error);
+        assert!(CurveSet::<f32, C>::read_csv("key,x,y\nNaN,0,0\n".as_bytes()).is_err());
+        // 0 and -0 are the same key
+        let set = CurveSet::<f32, C>::read_csv("key,x,y\n0,0,0\n-0,10,1\n".as_bytes()).unwrap();
+        assert_eq!(set.curves.len(), 1);
+
+        // keys which can't be told apart after the conversion to the type of the keys
+        let error = CurveSet::<i8, C>::read_csv("key,x,y\n1.2,0,0\n1.2,10,1\n1.4,0,0\n1.4,10,1\n".as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("Keys 1.2 and 1.4"), "{}", error);
+    }
+}
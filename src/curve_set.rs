@@ -3,7 +3,11 @@ use crate::irregular_dynamic::IrregularDynamicCurve;
 use crate::{Curve, weighted_average, FnResult};
 use simple_error::{SimpleError, bail};
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
-use super::tree::{TreeData, SerdeFormat, NodeData, LeafData};
+use super::tree::{TreeData, SerdeFormat, NodeData, LeafData, encode_name, decode_name, strip_ext};
+use crate::storage::Storage;
+use crate::lazy_tree::MemorySize;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
 use std::fmt::{Display, Formatter};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -19,6 +23,7 @@ impl<T, C> CurveSet<T, C> where
     C: Curve + NodeData
 {
     pub const NAME : &'static str = "CurveSet";
+    const CURVE_PREFIX : &'static str = "curve_";
 
     pub fn new() -> Self {
         return Self {
@@ -91,6 +96,56 @@ impl<T, C> CurveSet<T, C> where
         return Ok(self.binary_search_by_x(x, 0, self.curves.len() - 1).1);
     }
 
+    /// Encode the keys and the curves in their compact format (see `Curve::serialize_compact`).
+    /// The result has the type 3, followed by the number of curves as u32 and, for each curve,
+    /// its key as f32, the length of the encoded curve as u32 and the encoded curve.
+    pub fn serialize_compact(&self) -> Vec<u8> {
+        let mut ret = vec![3]; // Type is 3 by definition
+        ret.extend(&(self.curves.len() as u32).to_le_bytes());
+        for (key, curve) in &self.curves {
+            let bytes = curve.serialize_compact();
+            ret.extend(&key.make_into_f32().to_le_bytes());
+            ret.extend(&(bytes.len() as u32).to_le_bytes());
+            ret.extend(&bytes);
+        }
+        ret
+    }
+
+    /// Decode a curve set from the format of `serialize_compact`, using decode_curve for each
+    /// curve, e.g. `IrregularDynamicCurve::try_deserialize_compact`. Returns an error if the bytes
+    /// are not a valid curve set, including keys which are not finite or not strictly increasing.
+    pub fn try_deserialize_compact(bytes: &[u8], decode_curve: impl Fn(&[u8]) -> Result<C, SimpleError>) -> Result<Self, SimpleError> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], SimpleError> {
+            if bytes.len() < len {
+                bail!("Byte array too short for declared length.");
+            }
+            let (head, tail) = bytes.split_at(len);
+            *bytes = tail;
+            Ok(head)
+        }
+        fn take_u32(bytes: &mut &[u8]) -> Result<u32, SimpleError> {
+            Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+        }
+
+        let mut bytes = bytes;
+        let set_type = take(&mut bytes, 1)?[0];
+        if set_type != 3 {
+            bail!("Unknown curve set type {}.", set_type);
+        }
+        let count = take_u32(&mut bytes)?;
+        let mut set = Self::new();
+        for _ in 0..count {
+            let key = f32::from_bits(take_u32(&mut bytes)?);
+            if !key.is_finite() || matches!(set.curves.last(), Some((k, _)) if k.make_into_f32() >= key) {
+                bail!("Invalid key {}.", key);
+            }
+            let len = take_u32(&mut bytes)? as usize;
+            let curve = decode_curve(take(&mut bytes, len)?).map_err(|why| SimpleError::new(format!("Invalid curve for key {}: {}", key, why)))?;
+            set.curves.push((T::make_from_f32(key), curve));
+        }
+        Ok(set)
+    }
+
     pub fn add_curve(&mut self, x: T, curve: C) {
         if self.curves.is_empty() || x.make_into_f32() <= self.min_x() {
             self.curves.insert(0, (x, curve));
@@ -119,22 +174,64 @@ T: LikeANumber,
 C: Curve + Serialize + DeserializeOwned + LeafData,
 CurveSet<T, C>: NodeData
 {
-    fn save_tree(&self, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
+    const NAME : &'static str = CurveSet::<T, C>::NAME;
+
+    fn save_tree_to_storage(&self, storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
         if leaves.contains(&Self::NAME) {
-            self.save_to_file(dir_name, own_name, &format)?;
+            self.save_to_storage(storage, dir_name, &encode_name(own_name), format)?;
         } else {
-            let sub_dir_name = format!("{}/{}", dir_name, own_name);
+            let sub_dir_name = format!("{}/{}", dir_name, encode_name(own_name));
             for (key, curve) in &self.curves {
-                let file_name = format!("curve_{}", key.make_into_f32());
-                curve.save_to_file(&sub_dir_name, &file_name, &format)?;
+                let file_name = format!("{}{}", Self::CURVE_PREFIX, encode_name(&key.make_into_f32().to_string()));
+                curve.save_to_storage(storage, &sub_dir_name, &file_name, format)?;
             }
         }
 
         Ok(())
     }
 
-    fn load_tree(_dir_name: &str, _own_name: &str, _format: &SerdeFormat, _leaves: &Vec<&str>) -> FnResult<Self> {
-        bail!("Not yet implemented.");
+    fn load_tree_from_storage(storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self> {
+        if leaves.contains(&Self::NAME) {
+            return Ok(*Self::load_from_storage(storage, dir_name, &encode_name(own_name), format)?);
+        }
+
+        let sub_dir_name = format!("{}/{}", dir_name, encode_name(own_name));
+        let ext = C::get_ext(format);
+        let file_names = storage.list_files(&sub_dir_name)?;
+        // the same curve might be present with different compressions, but is loaded only
+        // once, in the compression chosen by `NodeData::load_from_storage`
+        let base_names : BTreeSet<&str> = file_names.iter().filter_map(|f| strip_ext(f, ext)).collect();
+        let mut curves = Vec::new();
+        for base_name in base_names {
+            // ignore everything that has not been written by save_tree
+            let encoded_key = match base_name.strip_prefix(Self::CURVE_PREFIX) {
+                Some(k) => k,
+                None => continue
+            };
+            let key : f32 = match decode_name(encoded_key)?.parse() {
+                Ok(k) => k,
+                Err(e) => bail!("Could not parse key of {}/{}: {}", sub_dir_name, base_name, e)
+            };
+            let curve = C::load_from_storage(storage, &sub_dir_name, base_name, format)?;
+            curves.push((T::make_from_f32(key), *curve));
+        }
+        curves.sort_by(|(k1, _), (k2, _)| k1.partial_cmp(k2).expect("NaN in curve set key"));
+        if let Some(pair) = curves.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            bail!("Duplicate key {} in {}", pair[0].0.make_into_f32(), sub_dir_name);
+        }
+
+        Ok(Self { curves })
+    }
+}
+
+impl<T, C> MemorySize for CurveSet<T, C> where 
+T: LikeANumber,
+C: Curve + NodeData + MemorySize
+{
+    fn memory_size(&self) -> usize {
+        let unused = self.curves.capacity() - self.curves.len();
+        std::mem::size_of::<Self>() + unused * std::mem::size_of::<(T, C)>() + 
+            self.curves.iter().map(|(_, c)| std::mem::size_of::<T>() + c.memory_size()).sum::<usize>()
     }
 }
 
@@ -145,7 +242,13 @@ C: Curve + Serialize + DeserializeOwned + LeafData
     fn get_ext(format: &SerdeFormat) -> &str {
         match format {
             SerdeFormat::Json => "json",
-            SerdeFormat::MessagePack => "crvs"
+            #[cfg(feature = "msgpack")]
+            SerdeFormat::MessagePack => "crvs",
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor => "crvs.cbor",
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode => "crvs.bincode",
+            SerdeFormat::Compressed(_, inner) => Self::get_ext(inner)
         }
     }
 }
@@ -162,4 +265,117 @@ C: Curve + Serialize + DeserializeOwned + LeafData + Display
         write!(f, ")")?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use crate::curve_set::CurveSet;
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+    use crate::tree::{TreeData, SerdeFormat};
+    use crate::{Curve, distance};
+
+    type TestSet = CurveSet<f32, IrregularDynamicCurve<f32, f32>>;
+
+    fn test_curve_set() -> TestSet {
+        let mut curve_set = TestSet::new();
+        for key in &[-72.5, 0.0, 10.0, 120.0] {
+            curve_set.add_curve(*key, IrregularDynamicCurve::new(vec![
+                Tup { x: *key, y: 0.0 },
+                Tup { x: *key + 30.0, y: 0.4 },
+                Tup { x: *key + 100.0, y: 1.0 },
+            ]));
+        }
+        curve_set
+    }
+
+    fn assert_same(a: &TestSet, b: &TestSet) {
+        assert_eq!(a.curves.len(), b.curves.len());
+        for ((k1, c1), (k2, c2)) in a.curves.iter().zip(b.curves.iter()) {
+            assert_eq!(k1, k2);
+            assert_eq!(distance(c1, c2), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let curve_set = test_curve_set();
+        let bytes = curve_set.serialize_compact();
+        let loaded = TestSet::try_deserialize_compact(&bytes, IrregularDynamicCurve::try_deserialize_compact).unwrap();
+        assert_eq!(loaded.curves.len(), 4);
+        for ((k1, c1), (k2, c2)) in curve_set.curves.iter().zip(loaded.curves.iter()) {
+            assert_eq!(k1, k2);
+            assert_eq!((c1.min_x(), c1.max_x()), (c2.min_x(), c2.max_x()));
+        }
+
+        assert!(TestSet::try_deserialize_compact(&bytes[..bytes.len() - 1], IrregularDynamicCurve::try_deserialize_compact).is_err());
+        // the first key is repeated as the second one
+        let mut repeated = bytes.clone();
+        let second = 1 + 4 + 4 + 4 + curve_set.curves[0].1.serialize_compact().len();
+        repeated.copy_within(5..9, second);
+        assert!(TestSet::try_deserialize_compact(&repeated, IrregularDynamicCurve::try_deserialize_compact).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_tree() {
+        let curve_set = test_curve_set();
+        let dir = std::env::temp_dir().join("dystonse-curves-test_save_and_load_tree");
+        let dir_name = dir.to_str().unwrap();
+        let own_name = "Bremer Straßenbahn AG";
+
+        let formats = [
+            SerdeFormat::Json, 
+            SerdeFormat::MessagePack,
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor,
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode,
+        ];
+        for format in &formats {
+            for leaves in &[vec![], vec![TestSet::NAME]] {
+                let _ = std::fs::remove_dir_all(&dir);
+                curve_set.save_tree(dir_name, own_name, format, leaves).unwrap();
+                let loaded = TestSet::load_tree(dir_name, own_name, format, leaves).unwrap();
+                assert_same(&curve_set, &loaded);
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    #[test]
+    fn test_save_and_load_compressed_tree() {
+        use crate::tree::Compression;
+
+        let curve_set = test_curve_set();
+        let dir = std::env::temp_dir().join("dystonse-curves-test_save_and_load_compressed_tree");
+        let dir_name = dir.to_str().unwrap();
+
+        for compression in Compression::ALL {
+            for leaves in &[vec![], vec![TestSet::NAME]] {
+                let _ = std::fs::remove_dir_all(&dir);
+                let format = SerdeFormat::MessagePack.compressed(*compression);
+                curve_set.save_tree(dir_name, "set", &format, leaves).unwrap();
+                let loaded = TestSet::load_tree(dir_name, "set", &format, leaves).unwrap();
+                assert_same(&curve_set, &loaded);
+
+                // compression is detected automatically
+                let loaded = TestSet::load_tree(dir_name, "set", &SerdeFormat::MessagePack, leaves).unwrap();
+                assert_same(&curve_set, &loaded);
+            }
+            assert!(dir.join(format!("set.crvs.{}", compression.get_ext())).is_file());
+
+            // if a curve exists with several compressions, the requested one is loaded
+            let _ = std::fs::remove_dir_all(&dir);
+            let mut other_set = TestSet::new();
+            for (key, _) in &curve_set.curves {
+                other_set.add_curve(*key, IrregularDynamicCurve::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 1.0, y: 1.0 }]));
+            }
+            let format = SerdeFormat::MessagePack.compressed(*compression);
+            curve_set.save_tree(dir_name, "set", &SerdeFormat::MessagePack, &vec![]).unwrap();
+            other_set.save_tree(dir_name, "set", &format, &vec![]).unwrap();
+            assert_same(&curve_set, &TestSet::load_tree(dir_name, "set", &SerdeFormat::MessagePack, &vec![]).unwrap());
+            assert_same(&other_set, &TestSet::load_tree(dir_name, "set", &format, &vec![]).unwrap());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,55 @@
+//! The error type of the curve types. With the `std` feature, this is `simple_error::SimpleError`,
+//! which the rest of the crate uses as well. Without std, it is replaced by a minimal type with
+//! the same interface.
+
+#[cfg(feature = "std")]
+pub use simple_error::{SimpleError, bail};
+
+#[cfg(not(feature = "std"))]
+pub use self::no_std::SimpleError;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use alloc::string::String;
+    use core::fmt;
+
+    #[derive(Debug, PartialEq)]
+    pub struct SimpleError {
+        err: String,
+    }
+
+    impl SimpleError {
+        pub fn new<T: Into<String>>(t: T) -> SimpleError {
+            SimpleError { err: t.into() }
+        }
+
+        pub fn as_str(&self) -> &str {
+            &self.err
+        }
+    }
+
+    impl From<&str> for SimpleError {
+        fn from(s: &str) -> SimpleError {
+            SimpleError { err: s.into() }
+        }
+    }
+
+    impl fmt::Display for SimpleError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.err.fmt(f)
+        }
+    }
+}
+
+/// Like `simple_error::bail`.
+#[cfg(not(feature = "std"))]
+macro_rules! bail {
+    ($e:expr) => {
+        return Err(::core::convert::From::from($e))
+    };
+    ($fmt:expr, $($arg:tt)+) => {
+        return Err(::core::convert::From::from($crate::error::SimpleError::new(format!($fmt, $($arg)+))))
+    };
+}
+#[cfg(not(feature = "std"))]
+pub(crate) use bail;
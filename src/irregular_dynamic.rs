@@ -1,10 +1,19 @@
 use crate::conversion::LikeANumber;
-use crate::{Curve, EPSILON};
+use crate::{Curve, EPSILON, trapezoid_area};
+use crate::error::{SimpleError, bail};
 use serde::{Serialize, Deserialize};
 use itertools::Itertools;
+#[cfg(feature = "std")]
 use crate::tree::{LeafData, SerdeFormat};
-use std::fmt::{Debug, Display, Formatter};
-use std::convert::TryInto;
+#[cfg(feature = "std")]
+use crate::lazy_tree::MemorySize;
+use alloc::vec::Vec;
+use alloc::collections::BinaryHeap;
+use core::fmt::{Debug, Display, Formatter};
+use core::convert::{TryInto, TryFrom};
+use core::cmp::Ordering;
+// instead of the inherent methods of f32, which are only available with std
+use num_traits::Float;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tup<X, Y> where 
@@ -15,18 +24,139 @@ pub struct Tup<X, Y> where
     pub y: Y,
 }
 
+/// The measure used to decide how much a simplified curve may deviate from the original.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimplificationMetric {
+    /// Perpendicular distance in (x, y) space. This mixes the units of x and y,
+    /// so the same tolerance behaves differently depending on the x range of the curve.
+    Perpendicular,
+    /// Maximum difference in probability (y) at any x.
+    Vertical,
+    /// Maximum difference in x at any probability, i.e. the maximum error of any quantile.
+    Horizontal,
+    /// Area between the original and the simplified curve, divided by the
+    /// x range of the curve. This is the mean absolute difference in probability.
+    Area,
+}
+
+/// The side of the original curve on which a one-sided simplification must stay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimplificationBound {
+    /// The simplified curve is everywhere greater than or equal to the original one.
+    Upper,
+    /// The simplified curve is everywhere less than or equal to the original one.
+    Lower,
+}
+
 /**
  * A curve that has a dynamic length and data points at regular distances.
  */
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "IrregularDynamicCurveData<X, Y>")]
 pub struct IrregularDynamicCurve<X, Y>
 where
     X: LikeANumber,
     Y: LikeANumber,
 {
+    pub(crate) points: Vec<Tup<X, Y>>,
+}
+
+/// The serialized layout of `IrregularDynamicCurve`, which is validated 
+/// (or repaired, see `repair::with_repair`) before it becomes a curve.
+#[derive(Deserialize)]
+struct IrregularDynamicCurveData<X, Y>
+where X: LikeANumber, Y: LikeANumber {
     points: Vec<Tup<X, Y>>,
 }
 
+impl<X, Y> TryFrom<IrregularDynamicCurveData<X, Y>> for IrregularDynamicCurve<X, Y>
+where X: LikeANumber, Y: LikeANumber
+{
+    type Error = SimpleError;
+
+    fn try_from(data: IrregularDynamicCurveData<X, Y>) -> Result<Self, SimpleError> {
+        Self::from_external(data.points)
+    }
+}
+
+/// A point that may be removed by `simplify_fixed_with_metric`, ordered such that 
+/// a `BinaryHeap` yields the candidate with the smallest error (and lowest index) first.
+struct RemovalCandidate {
+    error: f32,
+    index: usize,
+    generation: usize,
+}
+
+impl PartialEq for RemovalCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RemovalCandidate {}
+
+impl PartialOrd for RemovalCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RemovalCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.error.partial_cmp(&self.error).expect("NaN in curve")
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+/// The remaining points during a simplification, as a doubly linked list over the original indices.
+struct PointList {
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    keep: Vec<bool>,
+    head: usize,
+    tail: usize,
+}
+
+impl PointList {
+    fn new(len: usize) -> Self {
+        PointList {
+            prev: (0..len).map(|i| i.saturating_sub(1)).collect(),
+            next: (1..=len).collect(),
+            keep: vec![true; len],
+            head: 0,
+            tail: len - 1,
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.keep[i] = false;
+        if i == self.head {
+            self.head = self.next[i];
+        } else if i == self.tail {
+            self.tail = self.prev[i];
+        } else {
+            let (p, n) = (self.prev[i], self.next[i]);
+            self.next[p] = n;
+            self.prev[n] = p;
+        }
+    }
+
+    fn is_interior(&self, i: usize) -> bool {
+        self.keep[i] && i != self.head && i != self.tail
+    }
+}
+
+/// The ways in which `simplify_fixed_one_sided` can get rid of a point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OneSidedMove {
+    /// Remove the point, which lies on the correct side of the line between its neighbours.
+    Remove,
+    /// Move the point up to the y value of its successor, and remove the successor.
+    MergeNext,
+    /// Move the point down to the y value of its predecessor, and remove the predecessor.
+    MergePrev,
+}
+
 impl<X, Y> IrregularDynamicCurve<X, Y>
 where
     X: LikeANumber,
@@ -86,7 +216,21 @@ where
         return i;
     }
 
-    pub fn new(mut points: Vec<Tup<X, Y>>) -> Self {
+    pub fn new(points: Vec<Tup<X, Y>>) -> Self {
+        match Self::try_new(points) {
+            Ok(value) => value,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Like `new`, but returns an error instead of panicking if the points don't form a valid curve.
+    pub fn try_new(mut points: Vec<Tup<X, Y>>) -> Result<Self, SimpleError> {
+        if points.iter().any(|p| p.x.make_into_f32().is_nan()) {
+            bail!("Curve contains NaN as x value.");
+        }
+        if points.is_empty() {
+            bail!("Curve has no points.");
+        }
         points.sort_by(|p1, p2| p1.x.make_into_f32().partial_cmp(&p2.x.make_into_f32()).unwrap());
         // fix first and/or last point if they are very close to 0.0 / 1.0
         if points[0].y.make_into_f32().abs() < EPSILON {
@@ -97,19 +241,81 @@ where
             points[last_index].y = Y::make_from_f32(1.0);
         }
         let value = IrregularDynamicCurve { points };
-        value.check();
-        return value;
+        value.validate()?;
+        return Ok(value);
     }
 
-    fn check(&self) {
-        assert_eq!(self.points.first().unwrap().y.make_into_f32(), 0.0, "First point does not define y = 0.");
-        assert_eq!(self.points.last().unwrap().y.make_into_f32(), 1.0, "Last point does not define y = 1.");
-        for i in 0..self.points.len() - 1 {
-            let l = &self.points[i];
-            let r = &self.points[i + 1];
-            assert!(l.x < r.x, "Unsorted x values or duplicate x value.");
-            assert!(l.y <= r.y, "Y does not increase montonously for increasing x. Value goes from {:?} to {:?}", l.y, r.y);
+    /// Create a curve from points that have been read from an external source, which are 
+    /// validated, or repaired if this is called within `repair::with_repair`.
+    pub(crate) fn from_external(points: Vec<Tup<X, Y>>) -> Result<Self, SimpleError> {
+        #[cfg(feature = "std")]
+        if crate::repair::is_enabled() {
+            return Self::repair(points);
+        }
+        let curve = IrregularDynamicCurve { points };
+        curve.validate()?;
+        Ok(curve)
+    }
+
+    /// Create a curve from points that might violate the invariants checked by `new`.
+    /// Points with non-finite coordinates are dropped, the others are sorted by x, and of
+    /// several points with the same x, only the one with the highest y is kept. Then y is 
+    /// clamped to [0, 1] and made monotonous, and the first and last point are moved to 
+    /// y = 0 and y = 1. Fails if less than two points remain.
+    pub fn repair(points: Vec<Tup<X, Y>>) -> Result<Self, SimpleError> {
+        let mut points : Vec<(f32, f32)> = points.iter()
+            .map(|p| (p.x.make_into_f32(), p.y.make_into_f32()))
+            .filter(|(x, y)| x.is_finite() && y.is_finite())
+            .collect();
+        points.sort_by(|(x1, _), (x2, _)| x1.partial_cmp(x2).unwrap());
+        points.dedup_by(|(x2, y2), (x1, y1)| {
+            if x1 == x2 {
+                *y1 = y1.max(*y2);
+            }
+            x1 == x2
+        });
+        if points.len() < 2 {
+            bail!("Curve can't be repaired, it has only {} distinct finite points.", points.len());
+        }
+
+        let last_index = points.len() - 1;
+        let mut max_y = 0.0;
+        for (i, (_, y)) in points.iter_mut().enumerate() {
+            *y = if i == last_index { 1.0 } else { y.max(max_y).min(1.0) };
+            max_y = *y;
+        }
+        points[0].1 = 0.0;
+
+        let curve = IrregularDynamicCurve {
+            points: points.into_iter().map(|(x, y)| Tup { x: X::make_from_f32(x), y: Y::make_from_f32(y) }).collect()
+        };
+        // converting to X might have merged some x values
+        curve.validate()?;
+        return Ok(curve);
+    }
+
+    /// Check the invariants of the curve: it needs at least two points, sorted by strictly
+    /// increasing x, with monotonously increasing y from exactly 0 to exactly 1.
+    pub fn validate(&self) -> Result<(), SimpleError> {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => bail!("Curve has no points.")
+        };
+        for (i, (l, r)) in self.points.iter().tuple_windows().enumerate() {
+            if l.x.partial_cmp(&r.x) != Some(Ordering::Less) {
+                bail!("Unsorted x values or duplicate x value: point {} has x = {:?}, point {} has x = {:?}.", i, l.x, i + 1, r.x);
+            }
+            if !matches!(l.y.partial_cmp(&r.y), Some(Ordering::Less) | Some(Ordering::Equal)) {
+                bail!("Y does not increase montonously for increasing x. Value goes from {:?} to {:?} at x = {:?}.", l.y, r.y, r.x);
+            }
+        }
+        if first.y.make_into_f32() != 0.0 {
+            bail!("First point does not define y = 0, but y = {:?}.", first.y);
+        }
+        if last.y.make_into_f32() != 1.0 {
+            bail!("Last point does not define y = 1, but y = {:?}.", last.y);
         }
+        Ok(())
     }
 
     pub fn add_point(&mut self, x: f32, y: f32) {
@@ -134,51 +340,290 @@ where
         return self.points.len();
     }
 
+    /// Remove points whose perpendicular distance in (x, y) space from the simplified
+    /// curve is at most tol. Because x and y have different units, the effect of tol
+    /// depends on the x range of the curve. See `simplify_with_metric` for alternatives.
     pub fn simplify(&mut self, tol: f32) {
-        let mut delete_x : Vec<X> = Vec::new();
-        self.simplify_rec(tol, 0, self.len() - 1, &mut delete_x);
-        self.points.retain(|p| !delete_x.contains(&p.x));
+        self.simplify_with_metric(tol, SimplificationMetric::Perpendicular);
     }
 
-    fn simplify_rec(&mut self, tol: f32, start: usize, end: usize, delete_x: &mut Vec<X>) {
+    /// Remove points using the Douglas–Peucker algorithm, such that the error between
+    /// the original and the simplified curve, measured with the given metric, is at most tol.
+    pub fn simplify_with_metric(&mut self, tol: f32, metric: SimplificationMetric) {
+        self.simplify_sections(tol, metric, None);
+    }
+
+    /// Like `simplify_with_metric`, but the simplified curve is guaranteed to stay on one
+    /// side of the original curve, e.g. it never overstates any probability if bound
+    /// is `SimplificationBound::Lower`. This usually keeps more points than the two-sided variant.
+    pub fn simplify_one_sided(&mut self, tol: f32, metric: SimplificationMetric, bound: SimplificationBound) {
+        self.simplify_sections(tol, metric, Some(bound));
+    }
+
+    fn simplify_sections(&mut self, tol: f32, metric: SimplificationMetric, bound: Option<SimplificationBound>) {
+        let mut keep = vec![true; self.len()];
+
+        // sections are processed from an explicit stack instead of recursively, 
+        // so that large curves can't overflow the call stack
+        let mut sections = vec![(0, self.len() - 1)];
+        while let Some((start, end)) = sections.pop() {
+            match self.split_index(tol, metric, bound, start, end) {
+                Some(i) => {
+                    sections.push((start, i));
+                    sections.push((i, end));
+                },
+                None => { // discard all points in between
+                    for k in &mut keep[start + 1 .. end] {
+                        *k = false;
+                    }
+                }
+            }
+        }
+
+        self.retain_by_index(&keep);
+    }
+
+    /// Decide whether the points between start and end can be discarded. If so, returns None,
+    /// otherwise returns the index of the point at which the section needs to be split.
+    fn split_index(&self, tol: f32, metric: SimplificationMetric, bound: Option<SimplificationBound>, start: usize, end: usize) -> Option<usize> {
         if end - start < 2 { // keep all 1 or 2 points
-            return;
+            return None;
         }
         let mut max_d = -1.0;
         let mut max_d_i = 0;
+        let mut max_violation = 0.0;
+        let mut max_violation_i = None;
 
         let s = Self::tuple_to_f32(&self.points[start]);
         let e = Self::tuple_to_f32(&self.points[end]);
 
-        let n = Self::normal(&s, &e);
         for i in start+1 .. end {
-            let d = self.distance(start, end, i, n);
+            let p = Self::tuple_to_f32(&self.points[i]);
+            let d = Self::point_error(metric, &s, &e, &p);
             if d > max_d {
                 max_d = d;
                 max_d_i = i;
             }
-        }
 
-        if max_d <= tol { // discard all points in between
-            for i in start +1 .. end {
-                delete_x.push(self.points[i].x);
+            // for one-sided simplification, points on the wrong side of the line must be kept
+            let violation = match bound {
+                Some(SimplificationBound::Upper) => Self::vertical_offset(&s, &e, &p),
+                Some(SimplificationBound::Lower) => -Self::vertical_offset(&s, &e, &p),
+                None => 0.0
+            };
+            if violation > max_violation {
+                max_violation = violation;
+                max_violation_i = Some(i);
             }
-        } else {
-            self.simplify_rec(tol, start, max_d_i, delete_x);
-            self.simplify_rec(tol, max_d_i, end, delete_x);
         }
+
+        if max_violation_i.is_some() {
+            return max_violation_i;
+        }
+
+        let within_tolerance = match metric {
+            // Bounding the area of each section by tol times its width
+            // bounds the area of the whole curve by tol times the curve's width.
+            SimplificationMetric::Area => self.area_error(start, end) <= tol * (e.0 - s.0),
+            _ => max_d <= tol
+        };
+
+        if within_tolerance {
+            return None;
+        }
+        return Some(max_d_i);
     }
 
+    /// Remove points until at most max_points are left. This measures the 
+    /// perpendicular distance in (x, y) space, see `simplify_fixed_with_metric` for alternatives.
     pub fn simplify_fixed(&mut self, max_points: usize) {
-        while self.points.len() > max_points {
-            // find the triple of points with the least distance
-            let (min_distance_index, _min_distance) = self.points.iter().tuple_windows().map(
-                |(a,b,c)| Self::distance_three_points(a, b, c)
-            ).enumerate().min_by(
-                |(_, d1), (_, d2)| d1.partial_cmp(d2).expect("NaN in curve")
-            ).unwrap(); // can't be empty, unless max_points was < 0
-            self.points.remove(min_distance_index + 1);
+        self.simplify_fixed_with_metric(max_points, SimplificationMetric::Perpendicular);
+    }
+
+    /// Remove points until at most max_points are left, always removing the point
+    /// whose removal introduces the smallest error with regard to the given metric.
+    /// The first and the last point are never removed.
+    pub fn simplify_fixed_with_metric(&mut self, max_points: usize, metric: SimplificationMetric) {
+        let len = self.len();
+        if len <= max_points || len < 3 {
+            return;
+        }
+
+        let mut list = PointList::new(len);
+        // incremented whenever the error of a point changes, to skip outdated queue entries
+        let mut generation = vec![0; len];
+
+        let mut queue = BinaryHeap::with_capacity(len);
+        for i in 1 .. len - 1 {
+            let error = Self::error_three_points(metric, &self.points[i - 1], &self.points[i], &self.points[i + 1]);
+            queue.push(RemovalCandidate { error, index: i, generation: 0 });
+        }
+
+        let mut remaining = len;
+        while remaining > max_points {
+            let candidate = match queue.pop() {
+                Some(c) => c,
+                None => break
+            };
+            let i = candidate.index;
+            if !list.keep[i] || candidate.generation != generation[i] {
+                continue;
+            }
+
+            list.remove(i);
+            remaining -= 1;
+
+            // update the errors of the neighbours, unless they are the first or last point
+            for &j in &[list.prev[i], list.next[i]] {
+                if !list.is_interior(j) {
+                    continue;
+                }
+                generation[j] += 1;
+                let error = Self::error_three_points(metric, &self.points[list.prev[j]], &self.points[j], &self.points[list.next[j]]);
+                queue.push(RemovalCandidate { error, index: j, generation: generation[j] });
+            }
+        }
+
+        self.retain_by_index(&list.keep);
+    }
+
+    /// Reduce the curve to at most max_points (but at least two) points, such that the
+    /// simplified curve stays on one side of the original curve. In each step, the point 
+    /// whose removal adds the least area between the two curves is removed. If removing 
+    /// a point would cross the original curve, it is merged with one of its neighbours 
+    /// instead, so the first and last point may move inwards.
+    pub fn simplify_fixed_one_sided(&mut self, max_points: usize, bound: SimplificationBound) {
+        let len = self.len();
+        if len <= max_points || len < 3 {
+            return;
+        }
+
+        let mut list = PointList::new(len);
+        // incremented whenever the error of a point changes, to skip outdated queue entries
+        let mut generation = vec![0; len];
+
+        let mut queue = BinaryHeap::with_capacity(len);
+        for i in 1 .. len - 1 {
+            let (error, _) = self.one_sided_move(bound, &list, i);
+            queue.push(RemovalCandidate { error, index: i, generation: 0 });
+        }
+
+        let mut remaining = len;
+        while remaining > max_points {
+            let candidate = match queue.pop() {
+                Some(c) => c,
+                None => break
+            };
+            let i = candidate.index;
+            if !list.is_interior(i) || candidate.generation != generation[i] {
+                continue;
+            }
+
+            let center = match self.one_sided_move(bound, &list, i).1 {
+                OneSidedMove::Remove => {
+                    list.remove(i);
+                    list.prev[i]
+                },
+                OneSidedMove::MergeNext => {
+                    let n = list.next[i];
+                    self.points[i].y = self.points[n].y;
+                    list.remove(n);
+                    i
+                },
+                OneSidedMove::MergePrev => {
+                    let p = list.prev[i];
+                    self.points[i].y = self.points[p].y;
+                    list.remove(p);
+                    i
+                }
+            };
+            remaining -= 1;
+
+            // The move of a point depends on up to two points on each side,
+            // so everything within that range needs to be updated.
+            let mut j = center;
+            for _ in 0..2 {
+                if j != list.head {
+                    j = list.prev[j];
+                }
+            }
+            for _ in 0..5 {
+                if list.is_interior(j) {
+                    generation[j] += 1;
+                    let (error, _) = self.one_sided_move(bound, &list, j);
+                    queue.push(RemovalCandidate { error, index: j, generation: generation[j] });
+                }
+                if j == list.tail {
+                    break;
+                }
+                j = list.next[j];
+            }
         }
+
+        self.retain_by_index(&list.keep);
+    }
+
+    /// Find the cheapest way to get rid of the interior point i without crossing the original
+    /// curve, and the area that this adds between the simplified and the original curve.
+    fn one_sided_move(&self, bound: SimplificationBound, list: &PointList, i: usize) -> (f32, OneSidedMove) {
+        let (p, n) = (list.prev[i], list.next[i]);
+        let a = Self::tuple_to_f32(&self.points[p]);
+        let b = Self::tuple_to_f32(&self.points[i]);
+        let c = Self::tuple_to_f32(&self.points[n]);
+
+        let offset = Self::vertical_offset(&a, &c, &b);
+        let removable = match bound {
+            SimplificationBound::Upper => offset <= 0.0,
+            SimplificationBound::Lower => offset >= 0.0
+        };
+        let remove_cost = if removable {
+            // the area of the triangle a, b, c
+            offset.abs() * (c.0 - a.0) * 0.5
+        } else {
+            f32::INFINITY
+        };
+
+        // Moving a point up (or down) to the value of its neighbour never crosses the original curve.
+        let (old, new, merge) = match bound {
+            SimplificationBound::Upper => {
+                let merged = (b.0, c.1);
+                if n == list.tail {
+                    (vec![a, b, c], vec![a, merged, c], OneSidedMove::MergeNext)
+                } else {
+                    let d = Self::tuple_to_f32(&self.points[list.next[n]]);
+                    (vec![a, b, c, d], vec![a, merged, d], OneSidedMove::MergeNext)
+                }
+            },
+            SimplificationBound::Lower => {
+                let merged = (b.0, a.1);
+                if p == list.head {
+                    (vec![a, b, c], vec![a, merged, c], OneSidedMove::MergePrev)
+                } else {
+                    let z = Self::tuple_to_f32(&self.points[list.prev[p]]);
+                    (vec![z, a, b, c], vec![z, merged, c], OneSidedMove::MergePrev)
+                }
+            }
+        };
+        let merge_cost = (Self::polyline_area(&new) - Self::polyline_area(&old)).abs();
+
+        if remove_cost <= merge_cost {
+            return (remove_cost, OneSidedMove::Remove);
+        }
+        return (merge_cost, merge);
+    }
+
+    /// Compute the area below the polyline through the given points.
+    fn polyline_area(points: &[(f32, f32)]) -> f32 {
+        points.iter().tuple_windows().map(|(l, r)| (r.0 - l.0) * (l.1 + r.1) * 0.5).sum()
+    }
+
+    /// Keep only those points for which the corresponding entry of keep is true.
+    fn retain_by_index(&mut self, keep: &[bool]) {
+        let mut i = 0;
+        self.points.retain(|_| {
+            i += 1;
+            keep[i - 1]
+        });
     }
 
     fn normal(a: &(f32, f32), b: &(f32, f32)) ->  (f32, f32) {
@@ -189,22 +634,54 @@ where
         return (tup.x.make_into_f32(), tup.y.make_into_f32());
     }
 
-    fn distance_three_points(a: &Tup<X, Y>, b: &Tup<X, Y>, c: &Tup<X, Y>) -> f32 {
+    /// Compute the error that is introduced by removing b, so that a and c are directly connected.
+    fn error_three_points(metric: SimplificationMetric, a: &Tup<X, Y>, b: &Tup<X, Y>, c: &Tup<X, Y>) -> f32 {
         let a_f = Self::tuple_to_f32(a);
         let b_f = Self::tuple_to_f32(b);
         let c_f = Self::tuple_to_f32(c);
-        let n = Self::normal(&a_f, &c_f);
-        let a_minus_b = (b_f.0 - a_f.0, b_f.1 - a_f.1);
-        return ((a_minus_b.0 * n.0 + a_minus_b.1 * n.1) / (n.0 * n.0 + n.1 * n.1).sqrt()).abs();
+        Self::point_error(metric, &a_f, &c_f, &b_f)
+    }
+
+    /// Compute the distance of p to the line through s and e, using the given metric.
+    /// For `SimplificationMetric::Area`, this is the area of the triangle s, p, e, i.e. the
+    /// area that is introduced by removing p if it is the only point between s and e.
+    fn point_error(metric: SimplificationMetric, s: &(f32, f32), e: &(f32, f32), p: &(f32, f32)) -> f32 {
+        match metric {
+            SimplificationMetric::Perpendicular => {
+                // Formular adapted from https://www.mathelounge.de/521534/vektorenrechnung-abstand-zwischen-punkt-und-geraden-in-2d
+                let n = Self::normal(s, e);
+                let s_minus_p = (p.0 - s.0, p.1 - s.1);
+                ((s_minus_p.0 * n.0 + s_minus_p.1 * n.1) / Float::sqrt(n.0 * n.0 + n.1 * n.1)).abs()
+            },
+            SimplificationMetric::Vertical => Self::vertical_offset(s, e, p).abs(),
+            SimplificationMetric::Horizontal => {
+                if e.1 == s.1 {
+                    // The line is flat, so p (which lies between s and e) is on it.
+                    return 0.0;
+                }
+                let a = (p.1 - s.1) / (e.1 - s.1);
+                (p.0 - (s.0 * (1.0 - a) + e.0 * a)).abs()
+            },
+            SimplificationMetric::Area => Self::vertical_offset(s, e, p).abs() * (e.0 - s.0) * 0.5
+        }
     }
 
-    /// Compute the distance of p to the line throug s and e, where n is a normal vector of that line.
-    fn distance(&self, start: usize, _end: usize, i: usize, n: (f32, f32)) -> f32 {
-        // Formular adapted from https://www.mathelounge.de/521534/vektorenrechnung-abstand-zwischen-punkt-und-geraden-in-2d
+    /// Compute how far p lies above (positive) or below (negative) the line through s and e.
+    fn vertical_offset(s: &(f32, f32), e: &(f32, f32), p: &(f32, f32)) -> f32 {
+        let a = (p.0 - s.0) / (e.0 - s.0);
+        p.1 - (s.1 * (1.0 - a) + e.1 * a)
+    }
+
+    /// Compute the area between the line from start to end and the points in between.
+    fn area_error(&self, start: usize, end: usize) -> f32 {
         let s = Self::tuple_to_f32(&self.points[start]);
-        let p = Self::tuple_to_f32(&self.points[i]);
-        let s_minus_p = (p.0 - s.0, p.1 - s.1);
-        return ((s_minus_p.0 * n.0 + s_minus_p.1 * n.1) / (n.0 * n.0 + n.1 * n.1).sqrt()).abs();
+        let e = Self::tuple_to_f32(&self.points[end]);
+        (start..=end).map(|i| {
+            let p = Self::tuple_to_f32(&self.points[i]);
+            (p.0, Self::vertical_offset(&s, &e, &p))
+        }).tuple_windows().map(|((x1, dy1), (x2, dy2))| {
+            trapezoid_area(x2 - x1, dy1, dy2)
+        }).sum()
     }
 
     pub fn average(curves: &Vec<&IrregularDynamicCurve<f32, f32>>) -> IrregularDynamicCurve<f32, f32> {
@@ -215,10 +692,6 @@ where
         // gather x values from all curves:
         let x_values : Vec<f32> = curves.iter().map(|c| c.get_x_values()).kmerge().dedup().collect();
 
-        if x_values.len() > 1000 {
-            println!("Large curve average operation: {} curves have {} distinct x values.", curves.len(), x_values.len());
-        }
-
         // this is where the actual interpolation happens:
         let points = x_values.iter().map(|x| {
             let mut y = 0.0;
@@ -235,13 +708,58 @@ where
         return ret;
     }
 
+    /// type, min_x, max_x and the number of points
+    const COMPACT_HEADER_LEN : usize = 1 + 4 + 4 + 1;
+
+    /// The compact format has the type 1, followed by min_x and max_x as f32 (little-endian), the
+    /// number of points as u8, and two bytes for each point. The curve must have at most 255 points.
+    fn write_compact(&self) -> Vec<u8> {
+        let min_x = self.min_x();
+        let max_x = self.max_x();
+        
+        let mut ret = Vec::with_capacity(Self::COMPACT_HEADER_LEN + self.points.len() * 2);
+        ret.push(1 as u8); // Type is 1 by definition
+
+        ret.extend(&min_x.to_le_bytes());
+        ret.extend(&max_x.to_le_bytes());
+
+        ret.push(self.points.len() as u8);
+
+        for point in &self.points {
+            let x_f = point.x.make_into_f32();
+            let y_f = point.y.make_into_f32();
+            let x_b = ((x_f - min_x) / (max_x - min_x) * 255.0) as u8;
+            let y_b = (y_f * 255.0) as u8;
+            ret.push(x_b);
+            ret.push(y_b);
+        }
+
+        return ret;
+    }
+
     pub fn deserialize_compact(bytes: Vec<u8>) -> Self {
-        assert!(bytes[0] == 1); // check type
+        match Self::try_deserialize_compact(&bytes) {
+            Ok(value) => value,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Like `deserialize_compact`, but returns an error instead of panicking if the bytes 
+    /// are not a valid curve.
+    pub fn try_deserialize_compact(bytes: &[u8]) -> Result<Self, SimpleError> {
+        if bytes.len() < Self::COMPACT_HEADER_LEN {
+            bail!("Byte array too short for header.");
+        }
+        if bytes[0] != 1 {
+            bail!("Unknown curve type {}.", bytes[0]);
+        }
         let min_x = f32::from_le_bytes(bytes[1..5].try_into().unwrap());
         let max_x = f32::from_le_bytes(bytes[5..9].try_into().unwrap());
         let len = bytes[9] as usize;
 
-        assert!(bytes.len() >= 10 + 2 * len, "Byte array to short for declared length.");
+        if bytes.len() < 10 + 2 * len {
+            bail!("Byte array to short for declared length.");
+        }
 
         let mut points = Vec::with_capacity(len);
         let mut previous_x_b: i32 = -1;
@@ -260,7 +778,7 @@ where
             }
         }
 
-        IrregularDynamicCurve::new(points)
+        return Self::try_new(points);
     }
 }
 
@@ -317,61 +835,58 @@ where
     }
 
     fn serialize_compact(&self) -> Vec<u8> {
-        let min_x = self.min_x();
-        let max_x = self.max_x();
-        
-        let mut ret = Vec::with_capacity(self.points.len() * 2 + 10);
-        ret.push(1 as u8); // Type is 1 by definition
-
-        ret.extend(&min_x.to_le_bytes());
-        ret.extend(&max_x.to_le_bytes());
-
-        ret.push(self.points.len() as u8);
-
-        for point in &self.points {
-            let x_f = point.x.make_into_f32();
-            let y_f = point.y.make_into_f32();
-            let x_b = ((x_f - min_x) / (max_x - min_x) * 255.0) as u8;
-            let y_b = (y_f * 255.0) as u8;
-            ret.push(x_b);
-            ret.push(y_b);
-        }
-
-        return ret;
+        self.serialize_compact_limited(usize::MAX)
     }
 
+    /// If the curve has more points than fit into max_bytes, or than the 255 points which the
+    /// format can hold, it is simplified with `simplify_fixed`. At least two points are always written.
     fn serialize_compact_limited(&self, max_bytes: usize) -> Vec<u8> {
-        let max_points = (max_bytes - 10) / 2;
+        let max_points = (max_bytes.saturating_sub(Self::COMPACT_HEADER_LEN) / 2).clamp(2, u8::MAX as usize);
         if self.points.len() <= max_points {
-            return self.serialize_compact();
+            self.write_compact()
         } else {
             let mut clone = self.clone();
             clone.simplify_fixed(max_points);
-            return clone.serialize_compact();
+            clone.write_compact()
         }
     }
 }
 
+#[cfg(feature = "std")]
+impl<X, Y> MemorySize for IrregularDynamicCurve<X, Y>
+where X: LikeANumber, Y: LikeANumber
+{
+    fn memory_size(&self) -> usize {
+        core::mem::size_of::<Self>() + self.points.capacity() * core::mem::size_of::<Tup<X, Y>>()
+    }
+}
+
+#[cfg(feature = "std")]
 impl<X, Y> LeafData for IrregularDynamicCurve<X, Y>
 where X: LikeANumber, Y: LikeANumber
 {
     fn get_ext(format: &SerdeFormat) -> &str {
         match format {
             SerdeFormat::Json => "json",
-            SerdeFormat::MessagePack => "icrv"
+            #[cfg(feature = "msgpack")]
+            SerdeFormat::MessagePack => "icrv",
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor => "icrv.cbor",
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode => "icrv.bincode",
+            SerdeFormat::Compressed(_, inner) => Self::get_ext(inner)
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
-    use crate::{Curve};
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup, SimplificationMetric, SimplificationBound};
+    use crate::{Curve, distance};
     use assert_approx_eq::assert_approx_eq;
-    use gnuplot::{Figure, Caption, Color};
-
-    extern crate rand;
-
+    #[cfg(feature = "plot")]
+    use crate::plot::Plot;
+    #[cfg(feature = "plot")]
     use rand::Rng;
 
 
@@ -449,6 +964,40 @@ mod tests {
     }
 
     #[test]
+    fn test_try_new() {
+        type C = IrregularDynamicCurve<f32, f32>;
+        let c = C::try_new(vec![Tup { x: 10.0, y: 1.0 }, Tup { x: 0.0, y: 0.000_001 }]).unwrap();
+        assert_eq!(c.get_values_as_vectors(), (vec![0.0, 10.0], vec![0.0, 1.0]));
+        assert!(C::try_new(vec![]).is_err());
+        assert!(C::try_new(vec![Tup { x: f32::NAN, y: 0.0 }, Tup { x: 1.0, y: 1.0 }]).is_err());
+        assert!(C::try_new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 5.0, y: 0.7 }, Tup { x: 10.0, y: 0.5 }]).is_err());
+
+        let bytes = c.serialize_compact();
+        assert_eq!(C::try_deserialize_compact(&bytes).unwrap().get_values_as_vectors(), c.get_values_as_vectors());
+        assert!(C::try_deserialize_compact(&bytes[..bytes.len() - 1]).is_err());
+        assert!(C::try_deserialize_compact(&[1]).is_err());
+        assert!(C::try_deserialize_compact(&[2; 12]).is_err());
+    }
+
+    #[test]
+    fn test_compact_limits() {
+        type C = IrregularDynamicCurve<f32, f32>;
+        // more points than the number of points in the format can count
+        let c = C::new((0..=300).map(|i| Tup { x: i as f32, y: (i * i) as f32 / 90000.0 }).collect());
+        let loaded = C::try_deserialize_compact(&c.serialize_compact()).unwrap();
+        assert!(loaded.len() <= 255 && loaded.len() > 200, "{}", loaded.len());
+        assert_eq!((loaded.min_x(), loaded.max_x()), (0.0, 300.0));
+
+        // limits below the size of the header still lead to a valid curve
+        for max_bytes in 0..14 {
+            let bytes = c.serialize_compact_limited(max_bytes);
+            assert_eq!(bytes.len(), 14);
+            assert_eq!(C::try_deserialize_compact(&bytes).unwrap().get_x_values(), vec![0.0, 300.0]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "plot")]
     fn test_many_points() {
         let points = vec![
             Tup { x: 0.0, y: 0.0 },
@@ -464,31 +1013,141 @@ mod tests {
             c.add_point(i as f32, y);
         }
         
-        let mut fg = Figure::new();
-        let axes = fg.axes2d();
-        
-        let c_plot = c.get_values_as_vectors();
-        axes.lines_points(&c_plot.0, &c_plot.1, &[Caption("C original"), Color("grey")]);
+        let original = c.clone();
 
         c.simplify(0.01);
         
-        let c_plot = c.get_values_as_vectors();
-        axes.lines_points(&c_plot.0, &c_plot.1, &[Caption("C simplified"), Color("red")]);
+        let simplified = c.clone();
 
         c.simplify(0.01);
         c.simplify(0.01);
         c.simplify(0.01);
         
-        let c_plot = c.get_values_as_vectors();
-        axes.lines_points(&c_plot.0, &c_plot.1, &[Caption("C over-simplified"), Color("green")]);
-
-        match fg.show() {
+        let mut plot = Plot::new();
+        plot.add_curve_with_color(&original, "C original", "grey")
+            .add_curve_with_color(&simplified, "C simplified", "red")
+            .add_curve_with_color(&c, "C over-simplified", "green");
+        match plot.save(std::env::temp_dir().join("dystonse_curves_test_many_points.svg")) {
             Ok(_) => {},
             Err(e) => {println!("Error: {}", e);}
         }
     }
 
+    fn s_shaped_curve(max_x: f32) -> IrregularDynamicCurve<f32, f32> {
+        s_shaped_curve_with_points(max_x, 200)
+    }
+
+    fn s_shaped_curve_with_points(max_x: f32, n: usize) -> IrregularDynamicCurve<f32, f32> {
+        let points = (0..=n).map(|i| {
+            let a = i as f32 / n as f32;
+            let y = a * a * (3.0 - 2.0 * a) + f32::sin(a * 40.0) * a * (1.0 - a) * 0.02;
+            Tup { x: a * max_x, y: y.clamp(0.0, 1.0) }
+        }).collect();
+        IrregularDynamicCurve::<f32, f32>::new(points)
+    }
+
+    #[test]
+    fn test_simplify_with_metric() {
+        let tol = 0.01;
+        for metric in &[SimplificationMetric::Vertical, SimplificationMetric::Horizontal, SimplificationMetric::Area] {
+            let original = s_shaped_curve(3600.0);
+            let mut c = original.clone();
+            c.simplify_with_metric(tol, *metric);
+            assert!(c.len() < original.len());
+
+            match metric {
+                SimplificationMetric::Vertical => {
+                    for x in original.get_x_values() {
+                        assert!((original.y_at_x(x) - c.y_at_x(x)).abs() <= tol + 0.0001);
+                    }
+                },
+                SimplificationMetric::Horizontal => {
+                    for i in 1..100 {
+                        let y = i as f32 / 100.0;
+                        assert!((original.x_at_y(y) - c.x_at_y(y)).abs() <= tol + 0.01);
+                    }
+                },
+                _ => {
+                    let width = original.max_x() - original.min_x();
+                    assert!(distance(&original, &c) / width <= tol + 0.0001);
+                }
+            }
+        }
+
+        // vertical and area errors do not depend on the x range of the curve
+        for metric in &[SimplificationMetric::Vertical, SimplificationMetric::Area] {
+            let mut short = s_shaped_curve(60.0);
+            let mut long = s_shaped_curve(3600.0);
+            short.simplify_with_metric(tol, *metric);
+            long.simplify_with_metric(tol, *metric);
+            assert_eq!(short.len(), long.len());
+        }
+
+        // per point, the area metric is the area of the triangle that is cut off by removing the point
+        type C = IrregularDynamicCurve<f32, f32>;
+        assert_eq!(C::point_error(SimplificationMetric::Area, &(0.0, 0.0), &(2.0, 1.0), &(1.0, 1.0)), 0.5);
+        assert_eq!(C::point_error(SimplificationMetric::Area, &(0.0, 0.0), &(2.0, 1.0), &(1.0, 0.5)), 0.0);
+    }
+
+    /// Assert that upper lies above lower everywhere, which is the case if it is true at all points of both curves.
+    fn assert_above(upper: &IrregularDynamicCurve<f32, f32>, lower: &IrregularDynamicCurve<f32, f32>) {
+        for x in upper.get_x_values().iter().chain(lower.get_x_values().iter()) {
+            assert!(upper.y_at_x(*x) >= lower.y_at_x(*x) - 0.00001, "Not above at x = {}", x);
+        }
+    }
+
+    #[test]
+    fn test_simplify_one_sided() {
+        let original = s_shaped_curve(3600.0);
+
+        let mut upper = original.clone();
+        upper.simplify_one_sided(0.01, SimplificationMetric::Vertical, SimplificationBound::Upper);
+        assert!(upper.len() < original.len());
+        assert_above(&upper, &original);
+        for x in original.get_x_values() {
+            assert!(upper.y_at_x(x) - original.y_at_x(x) <= 0.0101);
+        }
+
+        let mut lower = original.clone();
+        lower.simplify_one_sided(0.01, SimplificationMetric::Area, SimplificationBound::Lower);
+        assert!(lower.len() < original.len());
+        assert_above(&original, &lower);
+
+        for max_points in &[2, 3, 10] {
+            let mut upper = original.clone();
+            upper.simplify_fixed_one_sided(*max_points, SimplificationBound::Upper);
+            assert_eq!(upper.len(), *max_points);
+            upper.validate().unwrap();
+            assert_above(&upper, &original);
+
+            let mut lower = original.clone();
+            lower.simplify_fixed_one_sided(*max_points, SimplificationBound::Lower);
+            assert_eq!(lower.len(), *max_points);
+            lower.validate().unwrap();
+            assert_above(&original, &lower);
+        }
+    }
+
+    #[test]
+    fn test_simplify_large_curve() {
+        let original = s_shaped_curve_with_points(3600.0, 100_000);
+
+        let mut c = original.clone();
+        c.simplify_with_metric(0.001, SimplificationMetric::Vertical);
+        assert!(c.len() < 1000);
+        for x in original.get_x_values().iter().step_by(97) {
+            assert!((original.y_at_x(*x) - c.y_at_x(*x)).abs() <= 0.0011);
+        }
+
+        let mut c = original.clone();
+        c.simplify_fixed(50);
+        assert_eq!(c.len(), 50);
+        assert_eq!(c.min_x(), original.min_x());
+        assert_eq!(c.max_x(), original.max_x());
+    }
+
     #[test]
+    #[cfg(feature = "plot")]
     fn test_fixed_simplification() {
         let points = vec![
             Tup { x: 0.0, y: 0.0 },
@@ -506,12 +1165,6 @@ mod tests {
             x += 1.0;
         }
         
-        let mut fg = Figure::new();
-        let axes = fg.axes2d();
-        
-        let c_plot = c.get_values_as_vectors();
-        axes.lines_points(&c_plot.0, &c_plot.1, &[Caption("C original"), Color("grey")]);
-
         // c.simplify_fixed(10);
 
         // assert!(c.points.len() <= 10);
@@ -524,11 +1177,10 @@ mod tests {
   
         let deser = IrregularDynamicCurve::<f32, f32>::deserialize_compact(ser);
 
-        let c_plot = deser.get_values_as_vectors();
-        axes.lines_points(&c_plot.0, &c_plot.1, &[Caption("C deserialized"), Color("green")]);
-
-
-        match fg.show() {
+        let mut plot = Plot::new();
+        plot.add_curve_with_color(&c, "C original", "grey")
+            .add_curve_with_color(&deser, "C deserialized", "green");
+        match plot.save(std::env::temp_dir().join("dystonse_curves_test_fixed_simplification.svg")) {
             Ok(_) => {},
             Err(e) => {println!("Error: {}", e);}
         }
@@ -537,7 +1189,7 @@ mod tests {
 
 impl<X, Y> Display for IrregularDynamicCurve<X, Y> where X: LikeANumber, Y: LikeANumber
 {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "IrregularDynamicCurve (min={:>5}, 5%={:>5}, med={:>5}, 95%={:>5}, max={:>5}) with {} points", 
         self.x_at_y(0.0) as i32, self.x_at_y(0.05) as i32, self.x_at_y(0.5) as i32, self.x_at_y(0.95) as i32, self.x_at_y(1.0) as i32, self.points.len())
     }
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, BTreeMap};
+use std::sync::{Arc, Mutex};
+
+use crate::FnResult;
+use crate::tree::{NodeData, LeafData, SerdeFormat, encode_name, decode_name, strip_ext};
+use crate::storage::{Storage, FileSystemStorage};
+
+/// Trait for objects that can estimate how much memory they occupy.
+pub trait MemorySize {
+    /// The approximate number of bytes used by this object, including its heap allocations.
+    fn memory_size(&self) -> usize;
+}
+
+/// A handle to a tree that has been saved with `TreeData::save_tree`, which loads the leaves 
+/// of type L on first access instead of loading the whole tree at once. Recently used leaves
+/// are kept in a cache, which is bounded by the sum of their `MemorySize::memory_size`.
+/// 
+/// The leaves are identified by their path below the root, e.g. `["Linie 4", "1000", "0"]`
+/// for a `CurveSet` that has been saved as a single file. Only files with the extension
+/// of L are indexed, so L should be one of the types in the leaves passed to `save_tree`, and
+/// the format should be one in which the extensions of the types differ. This is the case for
+/// every format except JSON, where all types share the extension `json`.
+pub struct LazyTree<L> {
+    storage: Box<dyn Storage + Send + Sync>,
+    format: SerdeFormat,
+    /// maps the path of each leaf to the directory and (encoded) file name without extension
+    index: HashMap<Vec<String>, (String, String)>,
+    cache: Mutex<LruCache<L>>,
+}
+
+impl<L> LazyTree<L> where
+L: NodeData + LeafData + MemorySize
+{
+    /// Index the tree that was saved with the given dir_name and own_name. Leaves will be 
+    /// cached as long as the memory size of all cached leaves is at most max_bytes.
+    pub fn open(dir_name: &str, own_name: &str, format: SerdeFormat, max_bytes: usize) -> FnResult<Self> {
+        Self::open_storage(Box::new(FileSystemStorage), dir_name, own_name, format, max_bytes)
+    }
+
+    /// Like `open`, for a tree that has been saved with `TreeData::save_tree_to_storage`.
+    pub fn open_storage(storage: Box<dyn Storage + Send + Sync>, dir_name: &str, own_name: &str, format: SerdeFormat, max_bytes: usize) -> FnResult<Self> {
+        let mut index = HashMap::new();
+        let ext = L::get_ext(&format).to_string();
+
+        // the root itself might have been saved as a single file
+        let encoded_own_name = encode_name(own_name);
+        for file_name in storage.list_files(dir_name)? {
+            if strip_ext(&file_name, &ext) == Some(&encoded_own_name) {
+                index.insert(Vec::new(), (dir_name.to_string(), encoded_own_name.clone()));
+            }
+        }
+
+        if storage.list_dirs(dir_name)?.contains(&encoded_own_name) {
+            let root_dir_name = format!("{}/{}", dir_name, encoded_own_name);
+            Self::index_dir(storage.as_ref(), &root_dir_name, &ext, &mut Vec::new(), &mut index)?;
+        }
+
+        Ok(LazyTree {
+            storage,
+            format,
+            index,
+            cache: Mutex::new(LruCache::new(max_bytes)),
+        })
+    }
+
+    fn index_dir(storage: &dyn Storage, dir_name: &str, ext: &str, path: &mut Vec<String>, index: &mut HashMap<Vec<String>, (String, String)>) -> FnResult<()> {
+        for sub_dir_name in storage.list_dirs(dir_name)? {
+            path.push(decode_name(&sub_dir_name)?);
+            Self::index_dir(storage, &format!("{}/{}", dir_name, sub_dir_name), ext, path, index)?;
+            path.pop();
+        }
+        for file_name in storage.list_files(dir_name)? {
+            if let Some(base_name) = strip_ext(&file_name, ext) {
+                let mut leaf_path = path.clone();
+                leaf_path.push(decode_name(base_name)?);
+                index.insert(leaf_path, (dir_name.to_string(), base_name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The paths of all leaves in the tree, in no particular order.
+    pub fn paths(&self) -> impl Iterator<Item = &Vec<String>> {
+        self.index.keys()
+    }
+
+    pub fn contains(&self, path: &[&str]) -> bool {
+        self.index.contains_key(&Self::owned_path(path))
+    }
+
+    /// Get the leaf at the given path, loading it if it is not cached. Returns `None` if 
+    /// there is no such leaf, and an error if it could not be loaded.
+    pub fn get(&self, path: &[&str]) -> FnResult<Option<Arc<L>>> {
+        let path = Self::owned_path(path);
+        let (dir_name, file_name) = match self.index.get(&path) {
+            Some(location) => location,
+            None => return Ok(None)
+        };
+
+        if let Some(leaf) = self.cache.lock().unwrap().get(&path) {
+            return Ok(Some(leaf));
+        }
+
+        // don't hold the lock while loading, so that other leaves can be accessed in the meantime
+        let leaf = Arc::new(*L::load_from_storage(self.storage.as_ref(), dir_name, file_name, &self.format)?);
+        let size = leaf.memory_size();
+        self.cache.lock().unwrap().insert(path, leaf.clone(), size);
+        Ok(Some(leaf))
+    }
+
+    /// The sum of the memory sizes of all cached leaves.
+    pub fn cached_bytes(&self) -> usize {
+        self.cache.lock().unwrap().used_bytes
+    }
+
+    /// The number of cached leaves.
+    pub fn cached_len(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    fn owned_path(path: &[&str]) -> Vec<String> {
+        path.iter().map(|s| s.to_string()).collect()
+    }
+}
+
+/// A least-recently-used cache which is bounded by the memory size of its entries.
+struct LruCache<L> {
+    max_bytes: usize,
+    used_bytes: usize,
+    /// incremented on every access, to determine the least recently used entry
+    tick: u64,
+    /// maps each path to the cached leaf, its memory size and the tick of its last use
+    entries: HashMap<Vec<String>, (Arc<L>, usize, u64)>,
+    /// maps the tick of the last use to the path of each entry
+    usage: BTreeMap<u64, Vec<String>>,
+}
+
+impl<L> LruCache<L> {
+    fn new(max_bytes: usize) -> Self {
+        LruCache {
+            max_bytes,
+            used_bytes: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, path: &[String]) -> Option<Arc<L>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (leaf, _, last_used) = self.entries.get_mut(path)?;
+        let key = self.usage.remove(last_used).unwrap();
+        self.usage.insert(tick, key);
+        *last_used = tick;
+        Some(leaf.clone())
+    }
+
+    fn insert(&mut self, path: Vec<String>, leaf: Arc<L>, size: usize) {
+        self.tick += 1;
+        // the leaf might have been inserted by another thread in the meantime
+        if let Some((_, old_size, last_used)) = self.entries.remove(&path) {
+            self.usage.remove(&last_used);
+            self.used_bytes -= old_size;
+        }
+        self.usage.insert(self.tick, path.clone());
+        self.entries.insert(path, (leaf, size, self.tick));
+        self.used_bytes += size;
+
+        while self.used_bytes > self.max_bytes {
+            let (&oldest, _) = self.usage.iter().next().unwrap();
+            let path = self.usage.remove(&oldest).unwrap();
+            let (_, size, _) = self.entries.remove(&path).unwrap();
+            self.used_bytes -= size;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use crate::lazy_tree::{LazyTree, MemorySize};
+    use crate::tree::{TreeData, SerdeFormat};
+    use crate::curve_set::CurveSet;
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+    use crate::Curve;
+    use std::collections::HashMap;
+
+    type TestSet = CurveSet<f32, IrregularDynamicCurve<f32, f32>>;
+
+    fn test_curve(offset: f32) -> IrregularDynamicCurve<f32, f32> {
+        IrregularDynamicCurve::new(vec![
+            Tup { x: offset, y: 0.0 },
+            Tup { x: offset + 50.0, y: 0.5 },
+            Tup { x: offset + 100.0, y: 1.0 },
+        ])
+    }
+
+    #[test]
+    fn test_lazy_tree() {
+        let mut routes = HashMap::<String, HashMap<String, TestSet>>::new();
+        for route in &["Linie 4", "Linie 6"] {
+            let mut stop_pairs = HashMap::new();
+            for (i, stop_pair) in ["Domsheide", "Brill", "Hauptbahnhof"].iter().enumerate() {
+                let mut set = TestSet::new();
+                set.add_curve(0.0, test_curve(i as f32));
+                set.add_curve(60.0, test_curve(i as f32 + 60.0));
+                stop_pairs.insert(stop_pair.to_string(), set);
+            }
+            routes.insert(route.to_string(), stop_pairs);
+        }
+
+        let dir = std::env::temp_dir().join("dystonse-curves-test_lazy_tree");
+        let dir_name = dir.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        routes.save_tree(dir_name, "routes", &SerdeFormat::MessagePack, &vec![TestSet::NAME]).unwrap();
+
+        // all loaded sets have the same size, which may differ from the sets that were saved
+        let set_size = LazyTree::<TestSet>::open(dir_name, "routes", SerdeFormat::MessagePack, 0).unwrap()
+            .get(&["Linie 4", "Brill"]).unwrap().unwrap().memory_size();
+        let tree = LazyTree::<TestSet>::open(dir_name, "routes", SerdeFormat::MessagePack, set_size * 2).unwrap();
+        assert_eq!(tree.paths().count(), 6);
+        assert!(tree.contains(&["Linie 6", "Hauptbahnhof"]));
+        assert_eq!(tree.cached_len(), 0);
+
+        let set = tree.get(&["Linie 4", "Brill"]).unwrap().unwrap();
+        assert_eq!(set.curves.len(), 2);
+        assert_eq!(set.curves[0].1.min_x(), 1.0);
+        assert_eq!(tree.cached_len(), 1);
+
+        tree.get(&["Linie 4", "Domsheide"]).unwrap().unwrap();
+        tree.get(&["Linie 4", "Brill"]).unwrap().unwrap();
+        tree.get(&["Linie 6", "Domsheide"]).unwrap().unwrap();
+        assert_eq!(tree.cached_len(), 2);
+        assert!(tree.cached_bytes() <= set_size * 2);
+
+        assert!(tree.get(&["Linie 8", "Brill"]).unwrap().is_none());
+
+        // the individual curves are leaves as well, if the curve sets have not been collapsed
+        let _ = std::fs::remove_dir_all(&dir);
+        routes.save_tree(dir_name, "routes", &SerdeFormat::MessagePack, &vec![]).unwrap();
+        let tree = LazyTree::<IrregularDynamicCurve<f32, f32>>::open(dir_name, "routes", SerdeFormat::MessagePack, 10000).unwrap();
+        assert_eq!(tree.paths().count(), 12);
+        let curve = tree.get(&["Linie 6", "Hauptbahnhof", "curve_60"]).unwrap().unwrap();
+        assert_eq!(curve.min_x(), 62.0);
+
+        // the extensions of curves and curve sets differ in the other binary formats as well
+        let formats = [
+            SerdeFormat::MessagePack,
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor,
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode,
+        ];
+        for format in &formats {
+            let _ = std::fs::remove_dir_all(&dir);
+            routes.save_tree(dir_name, "routes", format, &vec![]).unwrap();
+            let curves = LazyTree::<IrregularDynamicCurve<f32, f32>>::open(dir_name, "routes", format.clone(), 10000).unwrap();
+            assert_eq!(curves.paths().count(), 12);
+            let sets = LazyTree::<TestSet>::open(dir_name, "routes", format.clone(), 10000).unwrap();
+            assert_eq!(sets.paths().count(), 0);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
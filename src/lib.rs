@@ -1,20 +1,51 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+extern crate alloc;
+
 mod conversion;
+pub mod error;
 pub mod regular_dynamic;
 pub mod irregular_dynamic;
+#[cfg(feature = "std")]
 pub mod curve_set;
+#[cfg(feature = "std")]
 pub mod tree;
+#[cfg(feature = "std")]
+pub mod storage;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
+pub mod lazy_tree;
+#[cfg(feature = "std")]
+pub mod repair;
+pub mod svg;
+#[cfg(feature = "plot")]
+pub mod plot;
+#[cfg(feature = "csv")]
+pub mod csv_data;
+#[cfg(feature = "arrow")]
+pub mod arrow_data;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 pub use regular_dynamic::RegularDynamicCurve;
-pub use irregular_dynamic::{IrregularDynamicCurve, Tup};
+pub use irregular_dynamic::{IrregularDynamicCurve, Tup, SimplificationMetric, SimplificationBound};
+#[cfg(feature = "std")]
 pub use curve_set::CurveSet;
 
+use alloc::vec::Vec;
 use itertools::Itertools;
-use std::error::Error;
-use std::fmt::{Debug, Display};
+use core::fmt::{Debug, Display};
 
 const EPSILON: f32 = 0.0001;
 
-pub type FnResult<R> = std::result::Result<R, Box<dyn Error>>;
+/// The quantiles which are used to summarize curves (computed with `Curve::x_at_y`),
+/// along with short names for them, e.g. for the columns of `arrow_data::to_record_batch`.
+pub const QUANTILES : [(&str, f32); 5] = [("q05", 0.05), ("q25", 0.25), ("q50", 0.5), ("q75", 0.75), ("q95", 0.95)];
+
+#[cfg(feature = "std")]
+pub type FnResult<R> = std::result::Result<R, Box<dyn std::error::Error>>;
 
 /**
  * Trait to access the curve's values using f32 as type for X 
@@ -30,6 +61,13 @@ pub trait Curve : Debug + Display
     fn get_x_values(&self) -> Vec<f32>; // TODO return iterator instead of Vec
     fn serialize_compact(&self) -> Vec<u8>;
     fn serialize_compact_limited(&self, max_bytes: usize) -> Vec<u8>;
+
+    /// Draw a random x value which is distributed according to this curve.
+    #[cfg(feature = "random")]
+    fn sample(&self, rng: &mut dyn rand::RngCore) -> f32 {
+        use rand::Rng;
+        self.x_at_y(rng.gen())
+    }
 }
 
 /**
@@ -76,7 +114,7 @@ pub fn weighted_average(curves: Vec<&dyn Curve>, weights: Vec<f32>) -> Irregular
 }
 
 /// Compute the distance if two curves, defined as the area between the two
-pub fn distance(a: &impl Curve, b: &impl Curve) -> f32 {
+pub fn distance(a: &(impl Curve + ?Sized), b: &(impl Curve + ?Sized)) -> f32 {
     // gather x values from all curves:
     let x_a = a.get_x_values();
     let x_b = b.get_x_values();
@@ -88,21 +126,26 @@ pub fn distance(a: &impl Curve, b: &impl Curve) -> f32 {
         let y_b = b.y_at_x(*x);
         (x, y_a - y_b)
     }).tuple_windows().map(|((x1, dy1), (x2, dy2))| { 
-        // Consider sections of the two curves as trapezoids.
-        // The computation of the trapezoid's area and the
-        // naming of the variables follows 
-        // https://de.wikipedia.org/wiki/Trapez_(Geometrie)#%C3%9Cberschlagenes_oder_verschr%C3%A4nktes_Trapez
-        let h = x2 - x1;
-        let a = dy1.abs();
-        let c = dy2.abs();
-        if dy1 * dy2 >= 0.0 { // same sings, true trapezoid or triangle
-            (a + c) * h * 0.5
-        } else { // different signs, self-intersecting trapezoid
-            h * 0.5 * (a*a + c*c) / (a + c)
-        }
+        trapezoid_area(x2 - x1, dy1, dy2)
     }).sum()
 }
 
+/// Area between two line segments over a section of width h, where dy1 and dy2
+/// are the (signed) differences between the segments at the start and end of the section.
+pub(crate) fn trapezoid_area(h: f32, dy1: f32, dy2: f32) -> f32 {
+    // Consider sections of the two curves as trapezoids.
+    // The computation of the trapezoid's area and the
+    // naming of the variables follows 
+    // https://de.wikipedia.org/wiki/Trapez_(Geometrie)#%C3%9Cberschlagenes_oder_verschr%C3%A4nktes_Trapez
+    let a = dy1.abs();
+    let c = dy2.abs();
+    if dy1 * dy2 >= 0.0 { // same sings, true trapezoid or triangle
+        (a + c) * h * 0.5
+    } else { // different signs, self-intersecting trapezoid
+        h * 0.5 * (a*a + c*c) / (a + c)
+    }
+}
+
 // TODO Move tests into own file?
 // TODO Test multiple consecutive points with the same value
 // TODO split test functions
@@ -110,8 +153,8 @@ pub fn distance(a: &impl Curve, b: &impl Curve) -> f32 {
 mod tests {
     use crate::{Curve, TypedCurve, distance, weighted_average};
     use crate::regular_dynamic::RegularDynamicCurve;
-    use crate::irregular_dynamic::IrregularDynamicCurve;
-    use crate::irregular_dynamic::Tup;
+    #[cfg(feature = "std")]
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
     use crate::conversion::LikeANumber;
     use assert_approx_eq::assert_approx_eq;
     use fixed::types::{U1F7, U1F15};
@@ -265,6 +308,19 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "random")]
+    fn test_sample() {
+        use alloc::vec::Vec;
+        let c = RegularDynamicCurve::<f32, f32>::new(10.0, 0.0, vec!{0.0, 0.5, 1.0});
+        let mut rng = rand::thread_rng();
+        let samples : Vec<f32> = (0..1000).map(|_| c.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|x| *x >= 0.0 && *x <= 20.0));
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        assert!(mean > 9.0 && mean < 11.0, "{}", mean);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
     fn test_serde_reg() {
         let c1 = RegularDynamicCurve::<f32, f32>::new(
             10.0,
@@ -288,6 +344,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "std")]
     fn test_serde_irreg() {
         let c1 = IrregularDynamicCurve::<f32, f32>::new(
             vec!{
@@ -311,30 +368,69 @@ mod tests {
         assert!(distance(&c1, &deserialized_bin) == 0.0);
     }
 
-    // #[test]
-    // fn test_serde_curve_set() {
-    //     let mut f = File::open("/Users/lena/src/dystonse-gtfs-data/data/curve_img/Bremer Straßenbahn AG/Linie_4/4948172540786688452/curve_0_to_18.crv").unwrap();
-    //     let mut buffer = Vec::new();
-    //     // read the whole file
-    //     f.read_to_end(&mut buffer);
-
-    //     let curve_set: CurveSet<f32, IrregularDynamicCurve::<f32, f32>> = rmp_serde::from_read_ref(&buffer).unwrap();
-        
-
-    //     // Visualization of the test curves:
-    //     let mut fg = Figure::new();
-    //     let axes = fg.axes2d();
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_serde_validation() {
+        use crate::curve_set::CurveSet;
+        use crate::repair::with_repair;
+
+        type C = IrregularDynamicCurve::<f32, f32>;
+        let unsorted = r#"{"points":[{"x":10.0,"y":1.0},{"x":0.0,"y":0.0}]}"#;
+        let error = serde_json::from_str::<C>(unsorted).unwrap_err();
+        assert!(error.to_string().contains("Unsorted x values"), "{}", error);
+        let error = serde_json::from_str::<C>(r#"{"points":[{"x":0.0,"y":0.0},{"x":5.0,"y":0.7},{"x":8.0,"y":0.3},{"x":10.0,"y":1.0}]}"#).unwrap_err();
+        assert!(error.to_string().contains("from 0.7 to 0.3"), "{}", error);
+        assert!(serde_json::from_str::<C>(r#"{"points":[]}"#).is_err());
+
+        let c : C = with_repair(|| serde_json::from_str(unsorted)).unwrap();
+        assert_eq!(c.get_values_as_vectors(), (vec!{0.0, 10.0}, vec!{0.0, 1.0}));
+        // the repair mode only lasts for the closure
+        assert!(serde_json::from_str::<C>(unsorted).is_err());
+
+        let messy = r#"{"points":[{"x":5.0,"y":0.7},{"x":0.0,"y":-0.1},{"x":8.0,"y":0.3},{"x":5.0,"y":0.6},{"x":10.0,"y":0.9}]}"#;
+        let c : C = with_repair(|| serde_json::from_str(messy)).unwrap();
+        assert_eq!(c.get_values_as_vectors(), (vec!{0.0, 5.0, 8.0, 10.0}, vec!{0.0, 0.7, 0.7, 1.0}));
+        assert!(with_repair(|| serde_json::from_str::<C>(r#"{"points":[{"x":0.0,"y":0.0}]}"#)).is_err());
+
+        type R = RegularDynamicCurve::<f32, f32>;
+        let decreasing = r#"{"s":10.0,"x0":0.0,"y":[0.0,0.5,0.4,1.0]}"#;
+        let error = serde_json::from_str::<R>(decreasing).unwrap_err();
+        assert!(error.to_string().contains("from 0.5 to 0.4"), "{}", error);
+        assert!(serde_json::from_str::<R>(r#"{"s":0.0,"x0":0.0,"y":[0.0,1.0]}"#).is_err());
+        let r : R = with_repair(|| serde_json::from_str(decreasing)).unwrap();
+        assert_eq!(r.get_values_as_vectors().1, vec!{0.0, 0.5, 0.5, 1.0});
+
+        // curves nested in other types are validated as well
+        let set = r#"{"curves":[[0.0,{"points":[{"x":10.0,"y":1.0},{"x":0.0,"y":0.0}]}]]}"#;
+        assert!(serde_json::from_str::<CurveSet<f32, C>>(set).is_err());
+        let set : CurveSet<f32, C> = with_repair(|| serde_json::from_str(set)).unwrap();
+        assert_eq!(set.curves[0].1.min_x(), 0.0);
+    }
 
-    //     for (x, curve) in &curve_set.curves {
-    //         let c_plot = curve.get_values_as_vectors();
-    //         axes.lines_points(&c_plot.0, &c_plot.1, &[Caption(&format!("Ca. {:.1}s", x)), Color("red")]);
-    //     }
-        
-    //     for i in (-72..120).step_by(10) {
-    //         let c_plot = curve_set.curve_at_x_with_continuation(i as f32).get_values_as_vectors();
-    //         axes.lines_points(&c_plot.0, &c_plot.1, &[Caption(&format!("{} von 10", i)), Color("black")]);
-    //     }
+    #[test]
+    #[cfg(feature = "plot")]
+    fn test_serde_curve_set() {
+        use crate::curve_set::CurveSet;
+        use crate::plot::{CurveSetPlot, CurveSetView};
+
+        type C = IrregularDynamicCurve::<f32, f32>;
+        let mut curve_set = CurveSet::<f32, C>::new();
+        curve_set.add_curve(0.0, C::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 60.0, y: 0.8 }, Tup { x: 100.0, y: 1.0 }]));
+        curve_set.add_curve(18.0, C::new(vec![Tup { x: 20.0, y: 0.0 }, Tup { x: 120.0, y: 1.0 }]));
+
+        let buffer = rmp_serde::to_vec(&curve_set).unwrap();
+        let loaded : CurveSet<f32, C> = rmp_serde::from_read_ref(&buffer).unwrap();
+        assert_eq!(loaded.curves.len(), 2);
+        for ((key, curve), (loaded_key, loaded_curve)) in curve_set.curves.iter().zip(&loaded.curves) {
+            assert_eq!(key, loaded_key);
+            assert_eq!(curve.get_values_as_vectors(), loaded_curve.get_values_as_vectors());
+        }
 
-    //     fg.show();
-    // }
+        // the gnuplot commands for both views of the loaded set
+        for (view, style) in &[(CurveSetView::FanChart, "filledcurves"), (CurveSetView::Heatmap, "with image")] {
+            let mut commands = Vec::new();
+            CurveSetPlot::new(&loaded, *view).figure().unwrap().echo(&mut commands);
+            assert!(String::from_utf8_lossy(&commands).contains(style), "{:?}", view);
+        }
+    }
 }
\ No newline at end of file
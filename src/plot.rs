@@ -0,0 +1,489 @@
+//! Plotting of curves into SVG or PNG files, using gnuplot (which needs to be installed and in
+//! PATH). The images are rendered by gnuplot's `svg` and `pngcairo` terminals, so no display
+//! is needed, e.g. on servers and in CI. Curve sets can be shown as fan chart or heatmap with
+//! `CurveSetPlot`.
+
+use std::fs;
+use std::path::Path;
+
+use gnuplot::{AxesCommon, Figure, Caption, Color, Coordinate, LineStyle, DashType, ArrowType, ArrowheadType, PointSymbol, PointSize, AutoOption, FillAlpha, LineWidth};
+use simple_error::bail;
+
+use crate::{Curve, FnResult, QUANTILES};
+use crate::conversion::LikeANumber;
+use crate::curve_set::CurveSet;
+use crate::irregular_dynamic::IrregularDynamicCurve;
+use crate::tree::NodeData;
+use crate::storage::tmp_path;
+use crate::svg::Series;
+
+pub use crate::svg::DEFAULT_COLORS;
+
+/// The file formats into which a `Plot` can be saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Svg,
+    Png,
+}
+
+impl ImageFormat {
+    /// Choose the format by the extension of path, which has to be `svg` or `png`.
+    pub fn from_path(path: &Path) -> FnResult<Self> {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+            Some("svg") => Ok(ImageFormat::Svg),
+            Some("png") => Ok(ImageFormat::Png),
+            _ => bail!("Can't choose image format for {}, use the extension svg or png.", path.display())
+        }
+    }
+
+    fn terminal(&self, width: u32, height: u32) -> String {
+        match self {
+            ImageFormat::Svg => format!("svg size {},{} dynamic", width, height),
+            ImageFormat::Png => format!("pngcairo size {},{}", width, height),
+        }
+    }
+}
+
+/// A plot of one or more curves, with their y values (the cumulative probabilities) from 0 to 1
+/// on the y axis. Optionally, the quantiles of each curve are marked, e.g. with `QUANTILES`.
+pub struct Plot<'a> {
+    series: Vec<Series<'a>>,
+    title: Option<String>,
+    x_label: String,
+    y_label: String,
+    quantiles: Vec<(String, f32)>,
+    width: u32,
+    height: u32,
+}
+
+impl<'a> Plot<'a> {
+    pub fn new() -> Self {
+        Self {
+            series: Vec::new(),
+            title: None,
+            x_label: String::from("x"),
+            y_label: String::from("probability"),
+            quantiles: Vec::new(),
+            width: 800,
+            height: 600,
+        }
+    }
+
+    /// Add a curve, which is drawn in the next color of `DEFAULT_COLORS`.
+    pub fn add_curve(&mut self, curve: &'a dyn Curve, caption: &str) -> &mut Self {
+        let color = DEFAULT_COLORS[self.series.len() % DEFAULT_COLORS.len()];
+        self.add_curve_with_color(curve, caption, color)
+    }
+
+    /// Add a curve with a gnuplot color, e.g. `"red"` or `"#ff8000"`.
+    pub fn add_curve_with_color(&mut self, curve: &'a dyn Curve, caption: &str, color: &str) -> &mut Self {
+        self.series.push(Series { curve, caption: caption.to_string(), color: color.to_string() });
+        self
+    }
+
+    pub fn set_title(&mut self, title: &str) -> &mut Self {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn set_x_label(&mut self, label: &str) -> &mut Self {
+        self.x_label = label.to_string();
+        self
+    }
+
+    pub fn set_y_label(&mut self, label: &str) -> &mut Self {
+        self.y_label = label.to_string();
+        self
+    }
+
+    /// Mark the given quantiles, each with a dashed horizontal line at its y value, labeled
+    /// with its name, and a point where each curve crosses it.
+    pub fn set_quantile_markers(&mut self, quantiles: &[(&str, f32)]) -> &mut Self {
+        self.quantiles = quantiles.iter().map(|(name, y)| (name.to_string(), *y)).collect();
+        self
+    }
+
+    /// Mark the quantiles from `QUANTILES`, see `set_quantile_markers`.
+    pub fn with_default_quantile_markers(&mut self) -> &mut Self {
+        self.set_quantile_markers(&QUANTILES)
+    }
+
+    /// Set the size of the image in pixels, which is 800x600 by default.
+    pub fn set_size(&mut self, width: u32, height: u32) -> &mut Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Save the plot into a file, whose format is chosen by its extension (see
+    /// `ImageFormat::from_path`). This blocks until gnuplot has finished writing it.
+    pub fn save(&self, path: impl AsRef<Path>) -> FnResult<()> {
+        let path = path.as_ref();
+        let format = ImageFormat::from_path(path)?;
+        self.save_as(path, format)
+    }
+
+    /// Save the plot into a file with the given format, regardless of its extension.
+    pub fn save_as(&self, path: impl AsRef<Path>, format: ImageFormat) -> FnResult<()> {
+        if self.series.is_empty() {
+            bail!("Can't save a plot without curves.");
+        }
+        save_figure(self.figure(), path.as_ref(), format, self.width, self.height)
+    }
+
+    fn figure(&self) -> Figure {
+        let mut fg = Figure::new();
+        let axes = fg.axes2d();
+        if let Some(title) = &self.title {
+            axes.set_title(title, &[]);
+        }
+        axes.set_x_label(&self.x_label, &[]);
+        axes.set_y_label(&self.y_label, &[]);
+        axes.set_y_range(AutoOption::Fix(0.0), AutoOption::Fix(1.0));
+        axes.set_x_grid(true);
+        axes.set_y_grid(true);
+
+        for s in &self.series {
+            let (x, y) = s.curve.get_values_as_vectors();
+            axes.lines_points(&x, &y, &[Caption(&s.caption), Color(&s.color)]);
+        }
+
+        for (name, y) in &self.quantiles {
+            axes.arrow(Coordinate::Graph(0.0), Coordinate::Axis(*y as f64), Coordinate::Graph(1.0), Coordinate::Axis(*y as f64),
+                &[ArrowType(ArrowheadType::NoArrow), LineStyle(DashType::Dash), Color("grey")]);
+            axes.label(name, Coordinate::Graph(1.01), Coordinate::Axis(*y as f64), &[]);
+        }
+        if !self.quantiles.is_empty() {
+            let ys : Vec<f32> = self.quantiles.iter().map(|(_, y)| *y).collect();
+            for s in &self.series {
+                let xs : Vec<f32> = ys.iter().map(|y| s.curve.x_at_y(*y)).collect();
+                axes.points(&xs, &ys, &[PointSymbol('O'), PointSize(1.5), Color(&s.color)]);
+            }
+        }
+
+        fg
+    }
+}
+
+impl<'a> Default for Plot<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Save a plot of the curves, each with a caption, into a file, see `Plot::save`.
+pub fn plot_curves(curves: &[(&dyn Curve, &str)], path: impl AsRef<Path>) -> FnResult<()> {
+    let mut plot = Plot::new();
+    for (curve, caption) in curves {
+        plot.add_curve(*curve, caption);
+    }
+    plot.save(path)
+}
+
+/// Let gnuplot render the figure into a file, and wait until it's done.
+fn save_figure(mut fg: Figure, path: &Path, format: ImageFormat, width: u32, height: u32) -> FnResult<()> {
+    let path_str = match path.to_str() {
+        Some(s) => s,
+        None => bail!("Path {} is not valid UTF-8.", path.display())
+    };
+
+    // gnuplot doesn't report errors in its output, so we detect them by checking whether the file has been
+    // written, and render into a temporary file so that an existing file is only replaced on success
+    let tmp_path = tmp_path(path_str);
+    fg.set_terminal(&format.terminal(width, height), &tmp_path);
+    // the returned sentinel waits for gnuplot to exit when it's dropped
+    let result : FnResult<()> = fg.show().map(drop).map_err(|e| e.into()).and_then(|_| {
+        if fs::metadata(&tmp_path).map(|m| m.len()).unwrap_or(0) == 0 {
+            bail!("gnuplot did not write {}.", path.display());
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// How a `CurveSetPlot` shows the curves of the set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CurveSetView {
+    /// The key on the x axis, and bands between the 5% and 95% and between the 25% and 75%
+    /// quantiles of the curves on the y axis, with a line for the median.
+    FanChart,
+    /// The key on the x axis, the x values of the curves on the y axis, and their
+    /// probability density as color.
+    Heatmap,
+}
+
+/// The quantiles between which the bands of a fan chart are drawn, from the outermost band
+/// to the innermost, along with their opacity.
+const FAN_BANDS : [(f32, f32, f64); 2] = [(0.05, 0.95, 0.25), (0.25, 0.75, 0.5)];
+
+/// The sampled keys of a `CurveSetPlot` and the curves at these keys.
+type Samples = (Vec<f32>, Vec<IrregularDynamicCurve<f32, f32>>);
+
+/// A plot of a `CurveSet` over its keys. The set is sampled at `steps` evenly spaced keys
+/// from its first to its last key, using `CurveSet::curve_at_x_with_continuation`.
+pub struct CurveSetPlot<'a, T, C> where
+    T: LikeANumber,
+    C: Curve + NodeData
+{
+    set: &'a CurveSet<T, C>,
+    view: CurveSetView,
+    title: Option<String>,
+    key_label: String,
+    x_label: String,
+    color: String,
+    steps: usize,
+    width: u32,
+    height: u32,
+}
+
+impl<'a, T, C> CurveSetPlot<'a, T, C> where
+    T: LikeANumber,
+    C: Curve + NodeData
+{
+    pub fn new(set: &'a CurveSet<T, C>, view: CurveSetView) -> Self {
+        Self {
+            set,
+            view,
+            title: None,
+            key_label: String::from("key"),
+            x_label: String::from("x"),
+            color: String::from("blue"),
+            steps: 100,
+            width: 800,
+            height: 600,
+        }
+    }
+
+    pub fn set_title(&mut self, title: &str) -> &mut Self {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn set_view(&mut self, view: CurveSetView) -> &mut Self {
+        self.view = view;
+        self
+    }
+
+    /// Set the label of the keys, which are on the x axis.
+    pub fn set_key_label(&mut self, label: &str) -> &mut Self {
+        self.key_label = label.to_string();
+        self
+    }
+
+    /// Set the label of the x values of the curves, which are on the y axis.
+    pub fn set_x_label(&mut self, label: &str) -> &mut Self {
+        self.x_label = label.to_string();
+        self
+    }
+
+    /// Set the gnuplot color of the fan chart, which is blue by default.
+    pub fn set_color(&mut self, color: &str) -> &mut Self {
+        self.color = color.to_string();
+        self
+    }
+
+    /// Set the number of sampled keys, which is also the number of rows of the heatmap.
+    /// The default is 100.
+    pub fn set_steps(&mut self, steps: usize) -> &mut Self {
+        self.steps = steps;
+        self
+    }
+
+    /// Set the size of the image in pixels, which is 800x600 by default.
+    pub fn set_size(&mut self, width: u32, height: u32) -> &mut Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Save the plot into a file, whose format is chosen by its extension (see
+    /// `ImageFormat::from_path`). This blocks until gnuplot has finished writing it.
+    pub fn save(&self, path: impl AsRef<Path>) -> FnResult<()> {
+        let path = path.as_ref();
+        let format = ImageFormat::from_path(path)?;
+        self.save_as(path, format)
+    }
+
+    /// Save the plot into a file with the given format, regardless of its extension.
+    pub fn save_as(&self, path: impl AsRef<Path>, format: ImageFormat) -> FnResult<()> {
+        let fg = self.figure()?;
+        save_figure(fg, path.as_ref(), format, self.width, self.height)
+    }
+
+    fn samples(&self) -> FnResult<Samples> {
+        if self.set.curves.len() < 2 {
+            bail!("Can't plot a curve set with less than two curves.");
+        }
+        if self.steps < 2 {
+            bail!("Can't plot a curve set with less than two steps.");
+        }
+        let (min, max) = (self.set.min_x(), self.set.max_x());
+        let keys : Vec<f32> = (0..self.steps).map(|i| min + (max - min) * i as f32 / (self.steps - 1) as f32).collect();
+        let curves = keys.iter().map(|k| self.set.curve_at_x_with_continuation(*k)).collect();
+        Ok((keys, curves))
+    }
+
+    /// The x values of the curves at the given quantile, one for each sampled key.
+    fn quantile(curves: &[IrregularDynamicCurve<f32, f32>], y: f32) -> Vec<f32> {
+        curves.iter().map(|c| c.x_at_y(y)).collect()
+    }
+
+    /// The density of each cell of the heatmap, as row-major matrix with one row for each
+    /// of the `steps` x values, and one column for each sampled key. Also returns the x value
+    /// of the center of the first and last row.
+    fn density(&self, curves: &[IrregularDynamicCurve<f32, f32>]) -> (Vec<f32>, f32, f32) {
+        let min_x = curves.iter().map(|c| c.min_x()).fold(f32::INFINITY, f32::min);
+        let mut max_x = curves.iter().map(|c| c.max_x()).fold(f32::NEG_INFINITY, f32::max);
+        if max_x <= min_x {
+            max_x = min_x + 1.0;
+        }
+        let h = (max_x - min_x) / self.steps as f32;
+        let mut matrix = Vec::with_capacity(self.steps * curves.len());
+        for row in 0..self.steps {
+            let lower = min_x + row as f32 * h;
+            // the average density within the cell
+            matrix.extend(curves.iter().map(|c| (c.y_at_x(lower + h) - c.y_at_x(lower)) / h));
+        }
+        (matrix, min_x + h / 2.0, max_x - h / 2.0)
+    }
+
+    pub(crate) fn figure(&self) -> FnResult<Figure> {
+        let (keys, curves) = self.samples()?;
+        let mut fg = Figure::new();
+        let axes = fg.axes2d();
+        if let Some(title) = &self.title {
+            axes.set_title(title, &[]);
+        }
+        axes.set_x_label(&self.key_label, &[]);
+        axes.set_y_label(&self.x_label, &[]);
+
+        match self.view {
+            CurveSetView::FanChart => {
+                axes.set_x_grid(true);
+                axes.set_y_grid(true);
+                for (low, high, alpha) in &FAN_BANDS {
+                    let caption = format!("{}% to {}%", (low * 100.0).round(), (high * 100.0).round());
+                    axes.fill_between(&keys, Self::quantile(&curves, *low), Self::quantile(&curves, *high),
+                        &[Caption(&caption), Color(&self.color), FillAlpha(*alpha)]);
+                }
+                axes.lines(&keys, Self::quantile(&curves, 0.5), &[Caption("median"), Color(&self.color), LineWidth(2.0)]);
+            },
+            CurveSetView::Heatmap => {
+                let (matrix, first_x, last_x) = self.density(&curves);
+                axes.set_cb_label("density", &[]);
+                axes.image(matrix, self.steps, keys.len(),
+                    Some((keys[0] as f64, first_x as f64, keys[keys.len() - 1] as f64, last_x as f64)), &[]);
+            }
+        }
+
+        Ok(fg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plot::*;
+    use crate::irregular_dynamic::Tup;
+    use crate::regular_dynamic::RegularDynamicCurve;
+    use std::path::PathBuf;
+
+    fn commands(plot: &Plot) -> String {
+        let mut commands = Vec::new();
+        plot.figure().echo(&mut commands);
+        // the data is sent in binary after the commands
+        String::from_utf8_lossy(&commands).into_owned()
+    }
+
+    /// The tests which render files are skipped if gnuplot can't be started.
+    fn gnuplot_installed() -> bool {
+        std::process::Command::new("gnuplot").arg("--version").output().is_ok()
+    }
+
+    #[test]
+    fn test_plot() {
+        let a = IrregularDynamicCurve::<f32, f32>::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 100.0, y: 1.0 }]);
+        let b = RegularDynamicCurve::<f32, f32>::new(10.0, 10.0, vec![0.0, 0.6, 1.0]);
+        let mut plot = Plot::new();
+        plot.add_curve(&a, "linear")
+            .add_curve_with_color(&b, "regular", "#ff8000")
+            .set_title("Two curves")
+            .set_x_label("delay (s)")
+            .with_default_quantile_markers();
+
+        let commands = commands(&plot);
+        assert!(commands.contains("Two curves"));
+        assert!(commands.contains("delay (s)"));
+        assert!(commands.contains("linear"));
+        assert!(commands.contains("#ff8000"));
+        assert!(commands.contains("q95"));
+
+        assert_eq!(ImageFormat::from_path(&PathBuf::from("a.SVG")).unwrap(), ImageFormat::Svg);
+        assert_eq!(ImageFormat::from_path(&PathBuf::from("a.png")).unwrap(), ImageFormat::Png);
+        assert!(ImageFormat::from_path(&PathBuf::from("a.pdf")).is_err());
+
+        // a failed plot leaves an existing file alone
+        let empty = std::env::temp_dir().join("dystonse-curves-test_plot_empty.svg");
+        std::fs::write(&empty, "old").unwrap();
+        assert!(Plot::new().save(&empty).is_err());
+        assert_eq!(std::fs::read_to_string(&empty).unwrap(), "old");
+        std::fs::remove_file(&empty).unwrap();
+
+        if !gnuplot_installed() {
+            return;
+        }
+        let path = std::env::temp_dir().join("dystonse-curves-test_plot.svg");
+        plot.save(&path).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("<svg"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_curve_set_plot() {
+        let mut set = CurveSet::<f32, IrregularDynamicCurve<f32, f32>>::new();
+        set.add_curve(0.0, IrregularDynamicCurve::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 100.0, y: 1.0 }]));
+        set.add_curve(10.0, IrregularDynamicCurve::new(vec![Tup { x: 100.0, y: 0.0 }, Tup { x: 200.0, y: 1.0 }]));
+        let mut plot = CurveSetPlot::new(&set, CurveSetView::FanChart);
+        plot.set_steps(11).set_key_label("stop");
+
+        let (keys, curves) = plot.samples().unwrap();
+        assert_eq!(keys.len(), 11);
+        assert_eq!(keys[5], 5.0);
+        assert_eq!(CurveSetPlot::<f32, IrregularDynamicCurve<f32, f32>>::quantile(&curves, 0.5)[5], 100.0);
+
+        let mut commands = Vec::new();
+        plot.figure().unwrap().echo(&mut commands);
+        let commands = String::from_utf8_lossy(&commands);
+        assert!(commands.contains("filledcurves"));
+        assert!(commands.contains("5% to 95%"));
+        assert!(commands.contains("stop"));
+
+        // each column of the heatmap holds the density of one curve, which sums up to 1
+        plot.set_view(CurveSetView::Heatmap);
+        let (matrix, first_x, last_x) = plot.density(&curves);
+        assert_eq!(matrix.len(), 11 * 11);
+        let h = (last_x - first_x) / 10.0;
+        for col in 0..11 {
+            let sum : f32 = (0..11).map(|row| matrix[row * 11 + col] * h).sum();
+            assert!((sum - 1.0).abs() < 0.001, "{}", sum);
+        }
+        let mut commands = Vec::new();
+        plot.figure().unwrap().echo(&mut commands);
+        assert!(String::from_utf8_lossy(&commands).contains("with image"));
+
+        let mut single = CurveSet::<f32, IrregularDynamicCurve<f32, f32>>::new();
+        single.add_curve(0.0, curves[0].clone());
+        assert!(CurveSetPlot::new(&single, CurveSetView::FanChart).save(std::env::temp_dir().join("single.svg")).is_err());
+
+        if !gnuplot_installed() {
+            return;
+        }
+        let path = std::env::temp_dir().join("dystonse-curves-test_curve_set_plot.png");
+        plot.save(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
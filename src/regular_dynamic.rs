@@ -1,32 +1,69 @@
 use crate::conversion::LikeANumber;
 use crate::{Curve, TypedCurve};
-use gnuplot::{Figure, Caption, Color};
+use crate::error::{SimpleError, bail};
 use serde::{Serialize, Deserialize};
+#[cfg(feature = "std")]
 use crate::tree::{LeafData, SerdeFormat};
-use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "std")]
+use crate::lazy_tree::MemorySize;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Formatter};
+use core::convert::{TryFrom, TryInto};
+use core::cmp::Ordering;
+// instead of the inherent methods of f32, which are only available with std
+use num_traits::Float;
 
 /**
  * A curve that has a dynamic length and data points at regular distances.
  */
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "RegularDynamicCurveData<X, Y>")]
 pub struct RegularDynamicCurve<X, Y> 
+where X: LikeANumber, Y: LikeANumber {
+    pub(crate) s: X,
+    pub(crate) x0: X,
+    pub(crate) y: Vec<Y>
+}
+
+/// The serialized layout of `RegularDynamicCurve`, which is validated 
+/// (or repaired, see `repair::with_repair`) before it becomes a curve.
+#[derive(Deserialize)]
+struct RegularDynamicCurveData<X, Y>
 where X: LikeANumber, Y: LikeANumber {
     s: X,
     x0: X,
     y: Vec<Y>
 }
 
+impl<X, Y> TryFrom<RegularDynamicCurveData<X, Y>> for RegularDynamicCurve<X, Y>
+where X: LikeANumber, Y: LikeANumber
+{
+    type Error = SimpleError;
+
+    fn try_from(data: RegularDynamicCurveData<X, Y>) -> Result<Self, SimpleError> {
+        Self::from_external(data.s, data.x0, data.y)
+    }
+}
+
 impl<X, Y> RegularDynamicCurve<X, Y>
 where X: LikeANumber, Y: LikeANumber
 {
     pub fn new( s: f32, x0: f32, y: Vec<f32>) -> Self {
+        match Self::try_new(s, x0, y) {
+            Ok(value) => value,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Like `new`, but returns an error instead of panicking if the values don't form a valid curve.
+    pub fn try_new(s: f32, x0: f32, y: Vec<f32>) -> Result<Self, SimpleError> {
         let value = Self{
             s: X::make_from_f32(s),
             x0: X::make_from_f32(x0),
             y: y.iter().map(|yp| Y::make_from_f32(*yp)).collect()
         };
-        value.check();
-        return value;
+        value.validate()?;
+        return Ok(value);
     }
 
     pub fn typed_new(s: X, x0: X, y: Vec<Y>) -> Self {
@@ -35,30 +72,119 @@ where X: LikeANumber, Y: LikeANumber
         };
     }
 
-    // generates a graph of this curve and shows it in a gnuplot window
-    pub fn plot_curve_with_gnuplot(&self) {
-        let mut x = Vec::<f32>::new();
-        for i in 0..self.y.len() {
-            x.push(self.x0.make_into_f32()+(i as f32)*self.s.make_into_f32());
+    // generates a graph of this curve and saves it as svg or png, see `plot::Plot::save`
+    #[cfg(feature = "plot")]
+    pub fn plot_curve_with_gnuplot(&self, path: impl AsRef<std::path::Path>) -> crate::FnResult<()> {
+        let mut plot = crate::plot::Plot::new();
+        plot.add_curve(self, "A line");
+        plot.save(path)
+    }
+
+    /// Create a curve from values that have been read from an external source, which are 
+    /// validated, or repaired if this is called within `repair::with_repair`.
+    pub(crate) fn from_external(s: X, x0: X, y: Vec<Y>) -> Result<Self, SimpleError> {
+        #[cfg(feature = "std")]
+        if crate::repair::is_enabled() {
+            return Self::repair(s, x0, y);
         }
-        let y: Vec<f32> = self.y.iter().map(|yi| yi.make_into_f32()).collect();
-        let mut fg = Figure::new();
-        fg.axes2d()
-        .lines_points(&x, &y, &[Caption("A line"), Color("black")]);
-        match fg.show() {
-            Ok(_) => {},
-            Err(e) => {println!("Error: {}", e);}
+        let curve = Self::typed_new(s, x0, y);
+        curve.validate()?;
+        Ok(curve)
+    }
+
+    /// Create a curve from values that might violate the invariants checked by `new`.
+    /// Non-finite y values are replaced by their predecessor, then all y values are clamped 
+    /// to [0, 1] and made monotonous, and the first and last one are set to 0 and 1. 
+    /// Fails if s is not positive or if there are less than two y values.
+    pub fn repair(s: X, x0: X, y: Vec<Y>) -> Result<Self, SimpleError> {
+        if s.make_into_f32().partial_cmp(&0.0) != Some(Ordering::Greater) || !x0.make_into_f32().is_finite() {
+            bail!("Curve can't be repaired, it has s = {:?} and x0 = {:?}.", s, x0);
+        }
+        if y.len() < 2 {
+            bail!("Curve can't be repaired, it has only {} values.", y.len());
         }
+        let last_index = y.len() - 1;
+        let mut max_y = 0.0;
+        let y = y.iter().enumerate().map(|(i, yi)| {
+            let yi = yi.make_into_f32();
+            max_y = if i == last_index { 1.0 } else if yi.is_finite() { yi.max(max_y).min(1.0) } else { max_y };
+            Y::make_from_f32(if i == 0 { 0.0 } else { max_y })
+        }).collect();
+        let curve = Self::typed_new(s, x0, y);
+        curve.validate()?;
+        return Ok(curve);
     }
 
-    fn check(&self) {
-        assert_eq!(self.y.first().unwrap().make_into_f32(), 0.0, "First point does not define y = 0.");
-        assert_eq!(self.y.last().unwrap().make_into_f32(), 1.0, "Last point does not define y = 1.");
-        for i in 0..self.y.len() - 1 {
-            let l = &self.y[i];
-            let r = &self.y[i + 1];
-            assert!(l <= r, "Y does not increase montonously for increasing x.");
+    /// type, x0, s and the number of y values
+    const COMPACT_HEADER_LEN : usize = 1 + 4 + 4 + 2;
+
+    /// The compact format has the type 2, followed by x0 and s as f32 and the number of 
+    /// y values as u16 (all little-endian), and one byte for each y value.
+    fn write_compact(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(Self::COMPACT_HEADER_LEN + self.y.len());
+        ret.push(2); // Type is 2 by definition
+        ret.extend(&self.x0.make_into_f32().to_le_bytes());
+        ret.extend(&self.s.make_into_f32().to_le_bytes());
+        ret.extend(&(self.y.len() as u16).to_le_bytes());
+        for y in &self.y {
+            ret.push(Float::round(y.make_into_f32() * 255.0) as u8);
         }
+        ret
+    }
+
+    /// Decode a curve from the format of `serialize_compact`, or return an error if the 
+    /// bytes are not a valid curve.
+    pub fn try_deserialize_compact(bytes: &[u8]) -> Result<Self, SimpleError> {
+        if bytes.len() < Self::COMPACT_HEADER_LEN {
+            bail!("Byte array too short for header.");
+        }
+        if bytes[0] != 2 {
+            bail!("Unknown curve type {}.", bytes[0]);
+        }
+        let x0 = f32::from_le_bytes(bytes[1..5].try_into().unwrap());
+        let s = f32::from_le_bytes(bytes[5..9].try_into().unwrap());
+        let len = u16::from_le_bytes(bytes[9..11].try_into().unwrap()) as usize;
+        let values = match bytes.get(Self::COMPACT_HEADER_LEN..Self::COMPACT_HEADER_LEN + len) {
+            Some(values) => values,
+            None => bail!("Byte array too short for declared length.")
+        };
+        let y = values.iter().map(|b| Y::make_from_f32(*b as f32 / 255.0)).collect();
+        Self::from_external(X::make_from_f32(s), X::make_from_f32(x0), y)
+    }
+
+    /// A curve over the same range of x values, with len (at least two) equally spaced values.
+    fn resampled(&self, len: usize) -> Self {
+        let s = (self.max_x() - self.min_x()) / (len - 1) as f32;
+        let y = (0..len).map(|i| {
+            // the last value is set exactly, as rounding errors might miss the end of the curve
+            let y = if i == len - 1 { 1.0 } else { self.y_at_x(self.min_x() + i as f32 * s) };
+            Y::make_from_f32(y)
+        }).collect();
+        Self::typed_new(X::make_from_f32(s), self.x0, y)
+    }
+
+    /// Check the invariants of the curve: s has to be positive, and there have to be at least 
+    /// two y values, monotonously increasing from exactly 0 to exactly 1.
+    pub fn validate(&self) -> Result<(), SimpleError> {
+        if self.s.partial_cmp(&X::make_from_f32(0.0)) != Some(Ordering::Greater) {
+            bail!("Distance s = {:?} is not positive.", self.s);
+        }
+        let (first, last) = match (self.y.first(), self.y.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => bail!("Curve has no points.")
+        };
+        if first.make_into_f32() != 0.0 {
+            bail!("First point does not define y = 0, but y = {:?}.", first);
+        }
+        if last.make_into_f32() != 1.0 {
+            bail!("Last point does not define y = 1, but y = {:?}.", last);
+        }
+        for (i, (l, r)) in self.y.iter().zip(self.y.iter().skip(1)).enumerate() {
+            if !matches!(l.partial_cmp(r), Some(Ordering::Less) | Some(Ordering::Equal)) {
+                bail!("Y does not increase montonously for increasing x. Value goes from {:?} to {:?} at index {}.", l, r, i + 1);
+            }
+        }
+        Ok(())
     }
 }
 
@@ -85,8 +211,8 @@ where X: LikeANumber, Y: LikeANumber
 
         let i = (x - self.min_x()) / self.s.make_into_f32();
        
-        let i_min = i.floor() as usize;
-        let i_max = i.ceil() as usize;
+        let i_min = Float::floor(i) as usize;
+        let i_max = Float::ceil(i) as usize;
 
         if i_max == i_min {
             return self.y[i_min].make_into_f32();
@@ -152,11 +278,18 @@ where X: LikeANumber, Y: LikeANumber
     }
 
     fn serialize_compact(&self) -> Vec<u8> {
-        panic!("Not implemented for this type.");
+        self.serialize_compact_limited(usize::MAX)
     }
 
-    fn serialize_compact_limited(&self, _max_bytes: usize) -> Vec<u8> {
-        panic!("Not implemented for this type.");
+    /// If the curve has more values than fit into max_bytes, it is resampled with fewer values 
+    /// over the same range of x values. At least two values are always written.
+    fn serialize_compact_limited(&self, max_bytes: usize) -> Vec<u8> {
+        let max_values = max_bytes.saturating_sub(Self::COMPACT_HEADER_LEN).clamp(2, u16::MAX as usize);
+        if self.y.len() <= max_values {
+            self.write_compact()
+        } else {
+            self.resampled(max_values).write_compact()
+        }
     }
 }
 
@@ -183,8 +316,8 @@ where X: LikeANumber, Y: LikeANumber
 
         let i = X::make_into_f32(x - self.x0) / X::make_into_f32(self.s);
        
-        let i_min = i.floor() as usize;
-        let i_max = i.ceil() as usize;
+        let i_min = Float::floor(i) as usize;
+        let i_max = Float::ceil(i) as usize;
 
         if i_max == i_min {
             return self.y[i_min];
@@ -229,21 +362,65 @@ where X: LikeANumber, Y: LikeANumber
     }
 }
 
+#[cfg(feature = "std")]
+impl<X, Y> MemorySize for RegularDynamicCurve<X, Y>
+where X: LikeANumber, Y: LikeANumber
+{
+    fn memory_size(&self) -> usize {
+        core::mem::size_of::<Self>() + self.y.capacity() * core::mem::size_of::<Y>()
+    }
+}
+
+#[cfg(feature = "std")]
 impl<X, Y> LeafData for RegularDynamicCurve<X, Y> 
 where X: LikeANumber, Y: LikeANumber 
 {
     fn get_ext(format: &SerdeFormat) -> &str {
         match format {
             SerdeFormat::Json => "json",
-            SerdeFormat::MessagePack => "rcrv"
+            #[cfg(feature = "msgpack")]
+            SerdeFormat::MessagePack => "rcrv",
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor => "rcrv.cbor",
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode => "rcrv.bincode",
+            SerdeFormat::Compressed(_, inner) => Self::get_ext(inner)
         }
     }
 }
 
 impl<X, Y> Display for RegularDynamicCurve<X, Y> where X: LikeANumber, Y: LikeANumber
 {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "RegularDynamicCurve (min={:>5}, 5%={:>5}, med={:>5}, 95%={:>5}, max={:>5})", 
         self.x_at_y(0.0) as i32, self.x_at_y(0.05) as i32, self.x_at_y(0.5) as i32, self.x_at_y(0.95) as i32, self.x_at_y(1.0) as i32)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::regular_dynamic::RegularDynamicCurve;
+    use crate::Curve;
+
+    type C = RegularDynamicCurve<f32, f32>;
+
+    #[test]
+    fn test_compact() {
+        let curve = C::new(10.0, -5.0, vec![0.0, 0.2, 0.2, 0.9, 1.0]);
+        let bytes = curve.serialize_compact();
+        assert_eq!(bytes.len(), 11 + 5);
+        let loaded = C::try_deserialize_compact(&bytes).unwrap();
+        assert_eq!(loaded.get_x_values(), curve.get_x_values());
+        for (a, b) in loaded.y.iter().zip(curve.y.iter()) {
+            assert!((a - b).abs() <= 1.0 / 255.0);
+        }
+
+        // the curve is resampled over the same range
+        let limited = C::try_deserialize_compact(&curve.serialize_compact_limited(11 + 3)).unwrap();
+        assert_eq!(limited.get_x_values(), vec![-5.0, 15.0, 35.0]);
+        assert_eq!(limited.y_at_x(35.0), 1.0);
+
+        assert!(C::try_deserialize_compact(&bytes[..14]).is_err());
+        assert!(C::try_deserialize_compact(&[1; 20]).is_err());
+    }
+}
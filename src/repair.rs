@@ -0,0 +1,33 @@
+use std::cell::Cell;
+
+thread_local! {
+    static REPAIR: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run f in repair mode. Curves that are deserialized within f (on the current thread)
+/// and fail validation are repaired instead of rejected, e.g. by sorting their points
+/// and clamping their y values, see `IrregularDynamicCurve::repair` and
+/// `RegularDynamicCurve::repair`. Data that can't be repaired is still rejected.
+///
+/// ```
+/// # use dystonse_curves::{IrregularDynamicCurve, repair::with_repair};
+/// let json = r#"{"points":[{"x":10.0,"y":1.0},{"x":0.0,"y":0.0}]}"#;
+/// assert!(serde_json::from_str::<IrregularDynamicCurve<f32, f32>>(json).is_err());
+/// let curve : IrregularDynamicCurve<f32, f32> = with_repair(|| serde_json::from_str(json)).unwrap();
+/// ```
+pub fn with_repair<R>(f: impl FnOnce() -> R) -> R {
+    // restores the previous mode even if f panics
+    struct Reset(bool);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            REPAIR.with(|r| r.set(self.0));
+        }
+    }
+    let _reset = Reset(REPAIR.with(|r| r.replace(true)));
+    f()
+}
+
+/// Whether the current thread is within `with_repair`.
+pub(crate) fn is_enabled() -> bool {
+    REPAIR.with(|r| r.get())
+}
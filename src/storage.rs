@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::FnResult;
+use simple_error::bail;
+
+/// A place where `TreeData::save_tree_to_storage` can put the nodes of a tree. Each node is
+/// stored as a blob of bytes, identified by the name of a directory (with `/` as separator)
+/// and the name of a file within that directory (including its extensions). Directories
+/// don't need to be created explicitly, they exist as long as they contain files.
+pub trait Storage {
+    /// Store data in the given file, replacing it if it already exists.
+    fn write(&self, dir_name: &str, file_name: &str, data: &[u8]) -> FnResult<()>;
+    /// Get the data of the given file, or an error if it does not exist.
+    fn read(&self, dir_name: &str, file_name: &str) -> FnResult<Vec<u8>>;
+    /// Store the data that write_data writes in the given file, like `write`. The default 
+    /// implementation collects the data in memory first.
+    fn write_with(&self, dir_name: &str, file_name: &str, write_data: &mut dyn FnMut(&mut dyn Write) -> FnResult<()>) -> FnResult<()> {
+        let mut data = Vec::new();
+        write_data(&mut data)?;
+        self.write(dir_name, file_name, &data)
+    }
+    /// Get a reader for the data of the given file, like `read`. The default implementation 
+    /// reads all of the data into memory first.
+    fn reader(&self, dir_name: &str, file_name: &str) -> FnResult<Box<dyn Read + '_>> {
+        Ok(Box::new(std::io::Cursor::new(self.read(dir_name, file_name)?)))
+    }
+    /// Whether the given file exists.
+    fn exists(&self, dir_name: &str, file_name: &str) -> FnResult<bool>;
+    /// The names of all files directly within the given directory, in no particular order.
+    /// A directory that does not exist is treated as an empty one.
+    fn list_files(&self, dir_name: &str) -> FnResult<Vec<String>>;
+    /// The names of all directories directly within the given directory, in no particular order.
+    fn list_dirs(&self, dir_name: &str) -> FnResult<Vec<String>>;
+}
+
+/// A name for a temporary file next to path, which is unique among all threads and processes
+/// that write to path at the same time.
+pub(crate) fn tmp_path(path: &str) -> String {
+    static COUNTER : AtomicU64 = AtomicU64::new(0);
+    format!("{}.tmp{}_{}", path, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Stores each file as a file in the local file system, with dir_name used as a path.
+/// This is the layout that `TreeData::save_tree` has always used.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSystemStorage;
+
+impl FileSystemStorage {
+    /// List the entries of dir_name for which filter returns true. Entries whose names are not
+    /// valid unicode are skipped, as they can't have been written through this trait.
+    fn list(dir_name: &str, filter: impl Fn(&fs::FileType) -> bool) -> FnResult<Vec<String>> {
+        let mut names = Vec::new();
+        let entries = match fs::read_dir(dir_name) {
+            Ok(entries) => entries,
+            // like in the other storages, directories only exist implicitly
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(e.into())
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !filter(&entry.file_type()?) {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+impl Storage for FileSystemStorage {
+    /// Write to a temporary file next to the target, and then rename it to the target.
+    /// This way, a crash while writing never leaves a half-written file behind.
+    fn write(&self, dir_name: &str, file_name: &str, data: &[u8]) -> FnResult<()> {
+        self.write_with(dir_name, file_name, &mut |writer| Ok(writer.write_all(data)?))
+    }
+
+    fn read(&self, dir_name: &str, file_name: &str) -> FnResult<Vec<u8>> {
+        Ok(fs::read(format!("{}/{}", dir_name, file_name))?)
+    }
+
+    /// Like `write`, but the data is streamed into the temporary file instead of collecting it in memory.
+    fn write_with(&self, dir_name: &str, file_name: &str, write_data: &mut dyn FnMut(&mut dyn Write) -> FnResult<()>) -> FnResult<()> {
+        fs::create_dir_all(dir_name)?;
+        let file_path = format!("{}/{}", dir_name, file_name);
+        let tmp_path = tmp_path(&file_path);
+        let result = File::create(&tmp_path).map_err(|e| e.into()).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write_data(&mut writer)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &file_path)?;
+            Ok(())
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    fn reader(&self, dir_name: &str, file_name: &str) -> FnResult<Box<dyn Read + '_>> {
+        Ok(Box::new(BufReader::new(File::open(format!("{}/{}", dir_name, file_name))?)))
+    }
+
+    fn exists(&self, dir_name: &str, file_name: &str) -> FnResult<bool> {
+        Ok(Path::new(&format!("{}/{}", dir_name, file_name)).is_file())
+    }
+
+    fn list_files(&self, dir_name: &str) -> FnResult<Vec<String>> {
+        Self::list(dir_name, |t| t.is_file())
+    }
+
+    fn list_dirs(&self, dir_name: &str) -> FnResult<Vec<String>> {
+        Self::list(dir_name, |t| t.is_dir())
+    }
+}
+
+/// Keeps all files in memory, which is mostly useful for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<(String, String), Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of files that have been written.
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write(&self, dir_name: &str, file_name: &str, data: &[u8]) -> FnResult<()> {
+        self.files.lock().unwrap().insert((dir_name.to_string(), file_name.to_string()), data.to_vec());
+        Ok(())
+    }
+
+    fn read(&self, dir_name: &str, file_name: &str) -> FnResult<Vec<u8>> {
+        match self.files.lock().unwrap().get(&(dir_name.to_string(), file_name.to_string())) {
+            Some(data) => Ok(data.clone()),
+            None => bail!("No such file: {}/{}", dir_name, file_name)
+        }
+    }
+
+    fn exists(&self, dir_name: &str, file_name: &str) -> FnResult<bool> {
+        Ok(self.files.lock().unwrap().contains_key(&(dir_name.to_string(), file_name.to_string())))
+    }
+
+    fn list_files(&self, dir_name: &str) -> FnResult<Vec<String>> {
+        let files = self.files.lock().unwrap();
+        Ok(files.keys().filter(|(dir, _)| dir == dir_name).map(|(_, file)| file.clone()).collect())
+    }
+
+    fn list_dirs(&self, dir_name: &str) -> FnResult<Vec<String>> {
+        let files = self.files.lock().unwrap();
+        let dirs : BTreeSet<&str> = files.keys().filter_map(|(dir, _)| sub_dir_name(dir_name, dir)).collect();
+        Ok(dirs.into_iter().map(|d| d.to_string()).collect())
+    }
+}
+
+/// Stores all files as blobs in a single SQLite database, which avoids the overhead of
+/// millions of small files in the file system.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    /// Open (or create) the database at the given path.
+    pub fn open(path: &str) -> FnResult<Self> {
+        Self::from_connection(rusqlite::Connection::open(path)?)
+    }
+
+    /// Use an existing connection, creating the table for the files if necessary.
+    pub fn from_connection(connection: rusqlite::Connection) -> FnResult<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS files (
+                dir TEXT NOT NULL,
+                name TEXT NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (dir, name)
+            )"
+        )?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    fn write(&self, dir_name: &str, file_name: &str, data: &[u8]) -> FnResult<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO files (dir, name, data) VALUES (?1, ?2, ?3)",
+            rusqlite::params![dir_name, file_name, data]
+        )?;
+        Ok(())
+    }
+
+    fn read(&self, dir_name: &str, file_name: &str) -> FnResult<Vec<u8>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached("SELECT data FROM files WHERE dir = ?1 AND name = ?2")?;
+        let mut rows = statement.query(rusqlite::params![dir_name, file_name])?;
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => bail!("No such file: {}/{}", dir_name, file_name)
+        }
+    }
+
+    fn exists(&self, dir_name: &str, file_name: &str) -> FnResult<bool> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached("SELECT 1 FROM files WHERE dir = ?1 AND name = ?2")?;
+        Ok(statement.exists(rusqlite::params![dir_name, file_name])?)
+    }
+
+    fn list_files(&self, dir_name: &str) -> FnResult<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached("SELECT name FROM files WHERE dir = ?1")?;
+        let names = statement.query_map(rusqlite::params![dir_name], |row| row.get(0))?;
+        Ok(names.collect::<Result<_, _>>()?)
+    }
+
+    fn list_dirs(&self, dir_name: &str) -> FnResult<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        // the directories below dir_name sort between "dir_name/" and "dir_name0", as '0' follows '/'
+        let mut statement = connection.prepare_cached("SELECT DISTINCT dir FROM files WHERE dir > ?1 AND dir < ?2")?;
+        let dirs = statement.query_map(rusqlite::params![format!("{}/", dir_name), format!("{}0", dir_name)], |row| row.get(0))?;
+        let mut sub_dirs = BTreeSet::new();
+        for dir in dirs {
+            let dir : String = dir?;
+            if let Some(sub_dir) = sub_dir_name(dir_name, &dir) {
+                sub_dirs.insert(sub_dir.to_string());
+            }
+        }
+        Ok(sub_dirs.into_iter().collect())
+    }
+}
+
+/// If dir is below parent, return the name of the directory directly within parent that contains it.
+fn sub_dir_name<'a>(parent: &str, dir: &'a str) -> Option<&'a str> {
+    let rest = dir.strip_prefix(parent)?.strip_prefix('/')?;
+    return rest.split('/').next().filter(|name| !name.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Storage, FileSystemStorage, MemoryStorage};
+    use std::io::prelude::*;
+
+    /// Exercise the behaviour that all implementations of Storage must share.
+    fn check_storage(storage: &dyn Storage, root: &str) {
+        let dir = format!("{}/a", root);
+        let sub_dir = format!("{}/a/b/c", root);
+        storage.write(&dir, "one.txt", b"1").unwrap();
+        storage.write(&dir, "two.txt", b"2").unwrap();
+        storage.write(&dir, "two.txt", b"22").unwrap();
+        storage.write(&sub_dir, "three.txt", b"3").unwrap();
+        storage.write(&format!("{}/a-b", root), "four.txt", b"4").unwrap();
+
+        assert_eq!(storage.read(&dir, "two.txt").unwrap(), b"22");
+        assert_eq!(storage.read(&sub_dir, "three.txt").unwrap(), b"3");
+        assert!(storage.read(&dir, "three.txt").is_err());
+        assert!(storage.exists(&dir, "one.txt").unwrap());
+        assert!(!storage.exists(&dir, "three.txt").unwrap());
+
+        let mut files = storage.list_files(&dir).unwrap();
+        files.sort();
+        assert_eq!(files, vec!["one.txt", "two.txt"]);
+        assert_eq!(storage.list_dirs(&dir).unwrap(), vec!["b"]);
+        assert_eq!(storage.list_dirs(&format!("{}/a/b", root)).unwrap(), vec!["c"]);
+        assert!(storage.list_files(&format!("{}/a/b", root)).unwrap().is_empty());
+
+        // directories only exist implicitly, so a missing one is empty
+        assert!(storage.list_files(&format!("{}/missing", root)).unwrap().is_empty());
+        assert!(storage.list_dirs(&format!("{}/missing", root)).unwrap().is_empty());
+
+        storage.write_with(&dir, "five.txt", &mut |w| Ok(w.write_all(b"55555")?)).unwrap();
+        let mut data = Vec::new();
+        storage.reader(&dir, "five.txt").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"55555");
+        assert!(storage.reader(&dir, "six.txt").is_err());
+        assert!(storage.write_with(&dir, "six.txt", &mut |_| Err("failed".into())).is_err());
+        assert!(!storage.exists(&dir, "six.txt").unwrap());
+    }
+
+    #[test]
+    fn test_file_system_storage() {
+        let dir = std::env::temp_dir().join(format!("dystonse-curves-test_file_system_storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        check_storage(&FileSystemStorage, dir.to_str().unwrap());
+        // no temporary files are left, not even of the failed write
+        assert_eq!(std::fs::read_dir(dir.join("a")).unwrap().count(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let dir = std::env::temp_dir().join("dystonse-curves-test_concurrent_writes");
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir_name = dir.to_str().unwrap();
+        let threads : Vec<_> = (0..8u8).map(|i| {
+            let dir_name = dir_name.to_string();
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    FileSystemStorage.write(&dir_name, "file", &[i; 10000]).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // the file has been written completely by one of the threads, and no temporary files are left
+        let data = FileSystemStorage.read(dir_name, "file").unwrap();
+        assert_eq!(data.len(), 10000);
+        assert!(data.iter().all(|b| *b == data[0]));
+        assert_eq!(FileSystemStorage.list_files(dir_name).unwrap(), vec!["file"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        check_storage(&storage, "root");
+        assert_eq!(storage.len(), 5);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_storage() {
+        let storage = crate::storage::SqliteStorage::from_connection(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        check_storage(&storage, "root");
+    }
+}
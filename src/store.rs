@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::sync::Mutex;
+
+use crate::{FnResult, Curve};
+use crate::conversion::LikeANumber;
+use crate::irregular_dynamic::IrregularDynamicCurve;
+use crate::tree::{NodeData, SerdeFormat};
+use crate::storage::tmp_path;
+use simple_error::bail;
+
+/// Identifies a file written by `CurveStoreWriter`, followed by the version of its layout.
+const MAGIC : &[u8; 8] = b"DCRVSTOR";
+const VERSION : u32 = 1;
+/// magic, version, offset of the index and number of entries
+const HEADER_LEN : u64 = 8 + 4 + 8 + 8;
+
+/// Writes many curves, curve sets or other `NodeData` objects into a single file, each
+/// identified by a string key such as `"Linie 4/1000/0"`. The file consists of a header,
+/// the serialized objects one after another, and an index which maps each key to the
+/// position of its object. Use `CurveStore` to read it.
+/// 
+/// Curves are best added with `add_compact`, which stores them in the compact format of 
+/// `Curve::serialize_compact`. Curve sets and other objects have no such format, so they 
+/// are added with `add`, which serializes them with the format of the store.
+pub struct CurveStoreWriter {
+    path: String,
+    // the file has to be closed before the temporary file is removed, so it is dropped first
+    writer: BufWriter<File>,
+    tmp_file: TmpFile,
+    format: SerdeFormat,
+    index: HashMap<String, (u64, u64)>,
+    offset: u64,
+}
+
+impl CurveStoreWriter {
+    /// Start writing a store to path. The objects are serialized with the given format, which
+    /// should be a compact one such as `SerdeFormat::MessagePack`. The file at path is replaced
+    /// only when `finish` is called.
+    pub fn create(path: &str, format: SerdeFormat) -> FnResult<Self> {
+        let tmp_path = tmp_path(path);
+        let file = File::create(&tmp_path).map_err(|why| format!("couldn't create file {}: {}", tmp_path, why))?;
+        let mut writer = BufWriter::new(file);
+        // the header is written again by finish, when the position of the index is known
+        writer.write_all(&[0; HEADER_LEN as usize])?;
+        Ok(Self {
+            path: path.to_string(),
+            writer,
+            tmp_file: TmpFile { path: tmp_path, persisted: false },
+            format,
+            index: HashMap::new(),
+            offset: HEADER_LEN,
+        })
+    }
+
+    /// Add an object to the store, serialized with the format of the store. 
+    /// It is an error to add the same key twice.
+    pub fn add<T: NodeData>(&mut self, key: &str, value: &T) -> FnResult<()> {
+        let mut data = Vec::new();
+        value.save_to_writer(&mut data, &self.format)?;
+        self.add_bytes(key, &data)
+    }
+
+    /// Add a curve to the store in the compact format of `Curve::serialize_compact`, which
+    /// needs only two bytes per point, but keeps only 8 bits of precision for each coordinate.
+    /// It can only be read with `CurveStore::get_compact`. It is an error to add the same key twice.
+    pub fn add_compact<X, Y>(&mut self, key: &str, curve: &IrregularDynamicCurve<X, Y>) -> FnResult<()> where 
+    X: LikeANumber, Y: LikeANumber
+    {
+        self.add_bytes(key, &curve.serialize_compact())
+    }
+
+    fn add_bytes(&mut self, key: &str, data: &[u8]) -> FnResult<()> {
+        if self.index.contains_key(key) {
+            bail!("Duplicate key {} in {}", key, self.path);
+        }
+        self.writer.write_all(data)?;
+        self.index.insert(key.to_string(), (self.offset, data.len() as u64));
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// The number of objects that have been added so far.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Write the index and move the file into place. If the writer is dropped without 
+    /// calling this, the temporary file is removed and the file at path is left as it was.
+    pub fn finish(mut self) -> FnResult<()> {
+        let index_offset = self.offset;
+        for (key, (offset, len)) in &self.index {
+            self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
+            self.writer.write_all(key.as_bytes())?;
+            self.writer.write_all(&offset.to_le_bytes())?;
+            self.writer.write_all(&len.to_le_bytes())?;
+        }
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.write_all(&VERSION.to_le_bytes())?;
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer.write_all(&(self.index.len() as u64).to_le_bytes())?;
+
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        self.tmp_file.persist(&self.path)?;
+        Ok(())
+    }
+}
+
+/// A temporary file, which is removed when it is dropped before it has been moved into place.
+struct TmpFile {
+    path: String,
+    persisted: bool,
+}
+
+impl TmpFile {
+    fn persist(&mut self, path: &str) -> std::io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Read access to a file written by `CurveStoreWriter`. Only the index is read when the
+/// store is opened, each object is read (and deserialized) when it is requested by its key.
+pub struct CurveStore {
+    data: StoreData,
+    format: SerdeFormat,
+    index: HashMap<String, (u64, u64)>,
+}
+
+enum StoreData {
+    File(Mutex<File>),
+    #[cfg(feature = "mmap")]
+    Mmap(memmap2::Mmap),
+}
+
+impl CurveStore {
+    /// Open the store at path, reading objects from the file when they are requested.
+    /// The format must be the one that was used to write the store.
+    pub fn open(path: &str, format: SerdeFormat) -> FnResult<Self> {
+        let mut file = File::open(path).map_err(|why| format!("couldn't open file {}: {}", path, why))?;
+        let index = Self::read_index(&mut file).map_err(|why| format!("couldn't read index of {}: {}", path, why))?;
+        Ok(Self {
+            data: StoreData::File(Mutex::new(file)),
+            format,
+            index,
+        })
+    }
+
+    /// Like `open`, but the file is memory-mapped, so that objects can be read without any
+    /// system calls. The file must not be modified while the store is open.
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap(path: &str, format: SerdeFormat) -> FnResult<Self> {
+        let mut file = File::open(path).map_err(|why| format!("couldn't open file {}: {}", path, why))?;
+        let index = Self::read_index(&mut file).map_err(|why| format!("couldn't read index of {}: {}", path, why))?;
+        // This is only safe as long as nobody else modifies the file. CurveStoreWriter never
+        // modifies an existing file, it replaces it instead, which doesn't affect the mapping.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        if let Some((key, _)) = index.iter().find(|(_, (offset, len))| !ends_within(*offset, *len, mmap.len() as u64)) {
+            bail!("Entry {} exceeds the end of {}", key, path);
+        }
+        Ok(Self {
+            data: StoreData::Mmap(mmap),
+            format,
+            index,
+        })
+    }
+
+    fn read_index(file: &mut File) -> FnResult<HashMap<String, (u64, u64)>> {
+        let mut header = [0; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            bail!("Not a curve store");
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into()?);
+        if version != VERSION {
+            bail!("Unsupported version {}", version);
+        }
+        let index_offset = u64::from_le_bytes(header[12..20].try_into()?);
+        let count = u64::from_le_bytes(header[20..28].try_into()?);
+
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_to_end(&mut bytes)?;
+        let mut reader = &bytes[..];
+        // every entry takes at least 20 bytes, so a corrupt count can't cause a huge allocation
+        let mut index = HashMap::with_capacity((count as usize).min(bytes.len() / 20));
+        for _ in 0..count {
+            let key_len = u32::from_le_bytes(take(&mut reader, 4)?.try_into()?) as usize;
+            let key = String::from_utf8(take(&mut reader, key_len)?.to_vec())?;
+            let offset = u64::from_le_bytes(take(&mut reader, 8)?.try_into()?);
+            let len = u64::from_le_bytes(take(&mut reader, 8)?.try_into()?);
+            if offset < HEADER_LEN || !ends_within(offset, len, index_offset) {
+                bail!("Entry {} is outside of the data section", key);
+            }
+            index.insert(key, (offset, len));
+        }
+        Ok(index)
+    }
+
+    /// The keys of all objects in the store, in no particular order.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.index.keys()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Get the object with the given key. Returns `None` if there is no such object, and an
+    /// error if it could not be read or deserialized as T.
+    pub fn get<T: NodeData>(&self, key: &str) -> FnResult<Option<T>> {
+        let value = self.with_bytes(key, |bytes| T::load_from_reader(bytes, &self.format))
+            .map_err(|why| format!("couldn't read entry {}: {}", key, why))?;
+        Ok(value.map(|value| *value))
+    }
+
+    /// Get the curve with the given key, which has been added with `CurveStoreWriter::add_compact`.
+    /// Returns `None` if there is no such curve, and an error if it could not be read or decoded.
+    pub fn get_compact<X, Y>(&self, key: &str) -> FnResult<Option<IrregularDynamicCurve<X, Y>>> where 
+    X: LikeANumber, Y: LikeANumber
+    {
+        let value = self.with_bytes(key, |bytes| Ok(IrregularDynamicCurve::try_deserialize_compact(bytes)?))
+            .map_err(|why| format!("couldn't read entry {}: {}", key, why))?;
+        Ok(value)
+    }
+
+    /// Call f with the bytes of the object with the given key, if there is one.
+    fn with_bytes<R>(&self, key: &str, f: impl FnOnce(&[u8]) -> FnResult<R>) -> FnResult<Option<R>> {
+        let (offset, len) = match self.index.get(key) {
+            Some(location) => *location,
+            None => return Ok(None)
+        };
+        let value = match &self.data {
+            StoreData::File(file) => {
+                let mut bytes = vec![0; len as usize];
+                let mut file = file.lock().unwrap();
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut bytes)?;
+                f(&bytes)?
+            },
+            #[cfg(feature = "mmap")]
+            StoreData::Mmap(mmap) => {
+                // the entries have been checked against the length of the mapping by open_mmap
+                let end = offset.checked_add(len).ok_or("Entry exceeds the end of the store")?;
+                f(&mmap[offset as usize .. end as usize])?
+            },
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Whether an entry at offset with len bytes ends at or before limit, without overflowing.
+fn ends_within(offset: u64, len: u64, limit: u64) -> bool {
+    matches!(offset.checked_add(len), Some(end) if end <= limit)
+}
+
+/// Split off the first len bytes of reader.
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> FnResult<&'a [u8]> {
+    if reader.len() < len {
+        bail!("Unexpected end of index");
+    }
+    let (head, tail) = reader.split_at(len);
+    *reader = tail;
+    return Ok(head);
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use crate::store::{CurveStore, CurveStoreWriter};
+    use crate::tree::SerdeFormat;
+    use crate::curve_set::CurveSet;
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+    use crate::Curve;
+    use std::convert::TryInto;
+
+    type TestSet = CurveSet<f32, IrregularDynamicCurve<f32, f32>>;
+
+    fn test_curve(offset: f32) -> IrregularDynamicCurve<f32, f32> {
+        IrregularDynamicCurve::new(vec![
+            Tup { x: offset, y: 0.0 },
+            Tup { x: offset + 50.0, y: 0.5 },
+            Tup { x: offset + 100.0, y: 1.0 },
+        ])
+    }
+
+    #[test]
+    fn test_curve_store() {
+        let dir = std::env::temp_dir().join("dystonse-curves-test_curve_store");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("curves.store");
+        let path = path.to_str().unwrap();
+
+        let mut writer = CurveStoreWriter::create(path, SerdeFormat::MessagePack).unwrap();
+        for route in 0..10 {
+            for trip in 0..10 {
+                writer.add(&format!("Linie {}/{}", route, trip), &test_curve((route * 10 + trip) as f32)).unwrap();
+            }
+        }
+        let mut set = TestSet::new();
+        set.add_curve(0.0, test_curve(0.0));
+        set.add_curve(60.0, test_curve(60.0));
+        writer.add("sets/Domsheide", &set).unwrap();
+        assert!(writer.add("sets/Domsheide", &set).is_err());
+        writer.add_compact("compact/Brill", &test_curve(20.0)).unwrap();
+        assert!(writer.add_compact("compact/Brill", &test_curve(20.0)).is_err());
+        assert_eq!(writer.len(), 102);
+        writer.finish().unwrap();
+
+        #[allow(unused_mut)] // only extended if the mmap feature is enabled
+        let mut stores = vec![CurveStore::open(path, SerdeFormat::MessagePack).unwrap()];
+        #[cfg(feature = "mmap")]
+        stores.push(CurveStore::open_mmap(path, SerdeFormat::MessagePack).unwrap());
+        for store in &stores {
+            assert_eq!(store.len(), 102);
+            assert!(store.contains("Linie 4/2"));
+            let curve : IrregularDynamicCurve<f32, f32> = store.get("Linie 4/2").unwrap().unwrap();
+            assert_eq!(curve.min_x(), 42.0);
+            let curve : IrregularDynamicCurve<f32, f32> = store.get("Linie 9/9").unwrap().unwrap();
+            assert_eq!(curve.max_x(), 199.0);
+            let loaded_set : TestSet = store.get("sets/Domsheide").unwrap().unwrap();
+            assert_eq!(loaded_set.curves.len(), 2);
+            assert!(store.get::<TestSet>("Linie 10/0").unwrap().is_none());
+            let compact : IrregularDynamicCurve<f32, f32> = store.get_compact("compact/Brill").unwrap().unwrap();
+            // the compact format is lossy, except for the ends of the curve
+            assert_eq!(compact.get_x_values().len(), 3);
+            assert_eq!((compact.min_x(), compact.max_x()), (20.0, 120.0));
+            assert!(store.get_compact::<f32, f32>("compact/Domsheide").unwrap().is_none());
+        }
+
+        std::fs::write(path, b"not a store").unwrap();
+        assert!(CurveStore::open(path, SerdeFormat::MessagePack).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_unfinished_curve_store() {
+        let dir = std::env::temp_dir().join("dystonse-curves-test_unfinished_curve_store");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("curves.store");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, b"old").unwrap();
+
+        // without finish, neither the old file nor the directory are changed
+        let mut writer = CurveStoreWriter::create(path, SerdeFormat::MessagePack).unwrap();
+        writer.add("Linie 4/0", &test_curve(0.0)).unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(std::fs::read(path).unwrap(), b"old");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_corrupt_curve_store() {
+        let dir = std::env::temp_dir().join("dystonse-curves-test_corrupt_curve_store");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("curves.store");
+        let path = path.to_str().unwrap();
+
+        let mut writer = CurveStoreWriter::create(path, SerdeFormat::MessagePack).unwrap();
+        writer.add("a", &test_curve(0.0)).unwrap();
+        writer.finish().unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let index_offset = u64::from_le_bytes(bytes[12..20].try_into().unwrap()) as usize;
+
+        // a huge number of entries, which must not be allocated in advance
+        let mut corrupt = bytes.clone();
+        corrupt[20..28].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(path, &corrupt).unwrap();
+        assert!(CurveStore::open(path, SerdeFormat::MessagePack).is_err());
+
+        // an entry that would end beyond u64::MAX
+        let mut corrupt = bytes.clone();
+        corrupt[index_offset + 5 .. index_offset + 13].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(path, &corrupt).unwrap();
+        assert!(CurveStore::open(path, SerdeFormat::MessagePack).is_err());
+        #[cfg(feature = "mmap")]
+        assert!(CurveStore::open_mmap(path, SerdeFormat::MessagePack).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,371 @@
+//! Rendering of curves into SVG images, without gnuplot or any other dependency, e.g. for
+//! reports and web pages. For more elaborate plots, see the `plot` module. Rendering only
+//! needs alloc, while saving into files and adding curve sets need the `std` feature.
+
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+// instead of the inherent methods of f32, which are only available with std
+use num_traits::Float;
+
+#[cfg(feature = "std")]
+use crate::conversion::LikeANumber;
+#[cfg(feature = "std")]
+use crate::curve_set::CurveSet;
+#[cfg(feature = "std")]
+use crate::tree::NodeData;
+#[cfg(feature = "std")]
+use crate::FnResult;
+#[cfg(feature = "std")]
+use crate::storage::tmp_path;
+#[cfg(feature = "std")]
+use simple_error::bail;
+use crate::Curve;
+
+/// The colors which are used for curves that have been added without a color, both here
+/// and in `plot::Plot`.
+pub const DEFAULT_COLORS : [&str; 8] = ["#000000", "#e41a1c", "#377eb8", "#4daf4a", "#ff7f00", "#984ea3", "#a65628", "#999999"];
+
+const MARGIN_LEFT : f32 = 70.0;
+const MARGIN_RIGHT : f32 = 20.0;
+const MARGIN_TOP : f32 = 40.0;
+const MARGIN_BOTTOM : f32 = 50.0;
+const LEGEND_LINE_HEIGHT : f32 = 18.0;
+
+/// What is drawn on the y axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SvgView {
+    /// The curves themselves, i.e. the cumulative probability from 0 to 1.
+    Cumulative,
+    /// The probability density, i.e. the slope of the curves, which is constant between
+    /// two points of a curve and therefore drawn as steps.
+    Density,
+}
+
+/// A curve with its caption and color, as shown by `SvgPlot` and `plot::Plot`.
+pub(crate) struct Series<'a> {
+    pub(crate) curve: &'a dyn Curve,
+    pub(crate) caption: String,
+    pub(crate) color: String,
+}
+
+/// An SVG image of one or more curves, with axes, grid and legend.
+pub struct SvgPlot<'a> {
+    series: Vec<Series<'a>>,
+    title: Option<String>,
+    x_label: String,
+    y_label: Option<String>,
+    view: SvgView,
+    width: u32,
+    height: u32,
+}
+
+impl<'a> SvgPlot<'a> {
+    pub fn new() -> Self {
+        Self {
+            series: Vec::new(),
+            title: None,
+            x_label: String::from("x"),
+            y_label: None,
+            view: SvgView::Cumulative,
+            width: 800,
+            height: 600,
+        }
+    }
+
+    /// Add a curve, which is drawn in the next color of `DEFAULT_COLORS`.
+    pub fn add_curve(&mut self, curve: &'a dyn Curve, caption: &str) -> &mut Self {
+        let color = DEFAULT_COLORS[self.series.len() % DEFAULT_COLORS.len()];
+        self.add_curve_with_color(curve, caption, color)
+    }
+
+    /// Add a curve with an SVG color, e.g. `"red"` or `"#ff8000"`.
+    pub fn add_curve_with_color(&mut self, curve: &'a dyn Curve, caption: &str, color: &str) -> &mut Self {
+        self.series.push(Series { curve, caption: caption.to_string(), color: color.to_string() });
+        self
+    }
+
+    /// Add all curves of the set, each captioned with the caption and its key.
+    #[cfg(feature = "std")]
+    pub fn add_curve_set<T, C>(&mut self, set: &'a CurveSet<T, C>, caption: &str) -> &mut Self where
+        T: LikeANumber,
+        C: Curve + NodeData
+    {
+        for (key, curve) in &set.curves {
+            self.add_curve(curve, &format!("{} {}", caption, key.make_into_f32()));
+        }
+        self
+    }
+
+    pub fn set_title(&mut self, title: &str) -> &mut Self {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn set_x_label(&mut self, label: &str) -> &mut Self {
+        self.x_label = label.to_string();
+        self
+    }
+
+    /// Set the label of the y axis, which is "probability" or "density" by default,
+    /// depending on the view.
+    pub fn set_y_label(&mut self, label: &str) -> &mut Self {
+        self.y_label = Some(label.to_string());
+        self
+    }
+
+    pub fn set_view(&mut self, view: SvgView) -> &mut Self {
+        self.view = view;
+        self
+    }
+
+    /// Set the size of the image in pixels, which is 800x600 by default.
+    pub fn set_size(&mut self, width: u32, height: u32) -> &mut Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// The polyline of each series, in data coordinates.
+    fn lines(&self) -> Vec<Vec<(f32, f32)>> {
+        self.series.iter().map(|s| {
+            let (xs, ys) = s.curve.get_values_as_vectors();
+            match self.view {
+                SvgView::Cumulative => xs.into_iter().zip(ys).collect(),
+                SvgView::Density => {
+                    let mut line = Vec::new();
+                    for i in 1..xs.len() {
+                        let dx = xs[i] - xs[i - 1];
+                        // vertical segments would have an infinite density, so we leave them out
+                        if dx > 0.0 {
+                            let density = (ys[i] - ys[i - 1]) / dx;
+                            line.push((xs[i - 1], density));
+                            line.push((xs[i], density));
+                        }
+                    }
+                    line
+                }
+            }
+        }).collect()
+    }
+
+    /// Render the plot as an SVG document.
+    pub fn render(&self) -> String {
+        let lines = self.lines();
+        let points = lines.iter().flatten();
+        let (mut min_x, mut max_x) = points.clone().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), (x, _)| (min.min(*x), max.max(*x)));
+        if min_x > max_x {
+            min_x = 0.0;
+            max_x = 1.0;
+        } else if min_x == max_x {
+            min_x -= 1.0;
+            max_x += 1.0;
+        }
+        let max_y = match self.view {
+            SvgView::Cumulative => 1.0,
+            SvgView::Density => {
+                let max = points.fold(0.0, |max : f32, (_, y)| max.max(*y));
+                if max > 0.0 { max * 1.05 } else { 1.0 }
+            }
+        };
+
+        let width = self.width as f32;
+        let height = self.height as f32;
+        let plot_width = width - MARGIN_LEFT - MARGIN_RIGHT;
+        let plot_height = height - MARGIN_TOP - MARGIN_BOTTOM;
+        let px = |x: f32| MARGIN_LEFT + (x - min_x) / (max_x - min_x) * plot_width;
+        let py = |y: f32| MARGIN_TOP + (1.0 - y / max_y) * plot_height;
+
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" font-family=\"sans-serif\" font-size=\"12\">\n",
+            self.width, self.height, self.width, self.height);
+        svg.push_str(&format!("<rect width=\"{}\" height=\"{}\" fill=\"white\"/>\n", self.width, self.height));
+        if let Some(title) = &self.title {
+            svg.push_str(&format!("<text x=\"{:.2}\" y=\"24\" text-anchor=\"middle\" font-size=\"16\">{}</text>\n", width / 2.0, escape(title)));
+        }
+
+        // grid and tick labels
+        let x_decimals = decimals(min_x, max_x, 10);
+        for x in ticks(min_x, max_x, 10) {
+            svg.push_str(&format!("<line x1=\"{0:.2}\" y1=\"{1:.2}\" x2=\"{0:.2}\" y2=\"{2:.2}\" stroke=\"#dddddd\"/>\n", px(x), MARGIN_TOP, MARGIN_TOP + plot_height));
+            svg.push_str(&format!("<text x=\"{:.2}\" y=\"{:.2}\" text-anchor=\"middle\">{:.*}</text>\n", px(x), MARGIN_TOP + plot_height + 16.0, x_decimals, x));
+        }
+        let y_decimals = decimals(0.0, max_y, 10);
+        for y in ticks(0.0, max_y, 10) {
+            svg.push_str(&format!("<line x1=\"{0:.2}\" y1=\"{1:.2}\" x2=\"{2:.2}\" y2=\"{1:.2}\" stroke=\"#dddddd\"/>\n", MARGIN_LEFT, py(y), MARGIN_LEFT + plot_width));
+            svg.push_str(&format!("<text x=\"{:.2}\" y=\"{:.2}\" text-anchor=\"end\">{:.*}</text>\n", MARGIN_LEFT - 6.0, py(y) + 4.0, y_decimals, y));
+        }
+
+        // axes and their labels
+        svg.push_str(&format!("<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" fill=\"none\" stroke=\"black\"/>\n", MARGIN_LEFT, MARGIN_TOP, plot_width, plot_height));
+        svg.push_str(&format!("<text x=\"{:.2}\" y=\"{:.2}\" text-anchor=\"middle\">{}</text>\n", MARGIN_LEFT + plot_width / 2.0, height - 12.0, escape(&self.x_label)));
+        let y_label = match (&self.y_label, self.view) {
+            (Some(label), _) => label.as_str(),
+            (None, SvgView::Cumulative) => "probability",
+            (None, SvgView::Density) => "density",
+        };
+        svg.push_str(&format!("<text transform=\"translate(16,{:.2}) rotate(-90)\" text-anchor=\"middle\">{}</text>\n", MARGIN_TOP + plot_height / 2.0, escape(y_label)));
+
+        // the series, clipped to the plot area
+        svg.push_str(&format!("<clipPath id=\"plot-area\"><rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\"/></clipPath>\n", MARGIN_LEFT, MARGIN_TOP, plot_width, plot_height));
+        svg.push_str("<g clip-path=\"url(#plot-area)\" fill=\"none\" stroke-width=\"1.5\">\n");
+        for (s, line) in self.series.iter().zip(&lines) {
+            let coordinates : Vec<String> = line.iter().map(|(x, y)| format!("{:.2},{:.2}", px(*x), py(*y))).collect();
+            svg.push_str(&format!("<polyline stroke=\"{}\" points=\"{}\"/>\n", escape(&s.color), coordinates.join(" ")));
+        }
+        svg.push_str("</g>\n");
+
+        // the legend is placed where the curves are least likely to be: top left for
+        // cumulative curves, which rise from the bottom left, and top right for densities
+        if !self.series.is_empty() {
+            let legend_width = 40.0 + self.series.iter().map(|s| s.caption.chars().count()).max().unwrap_or(0) as f32 * 7.0;
+            let legend_height = self.series.len() as f32 * LEGEND_LINE_HEIGHT + 8.0;
+            let legend_x = match self.view {
+                SvgView::Cumulative => MARGIN_LEFT + 10.0,
+                SvgView::Density => MARGIN_LEFT + plot_width - 10.0 - legend_width,
+            };
+            let legend_y = MARGIN_TOP + 10.0;
+            svg.push_str(&format!("<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" fill=\"white\" fill-opacity=\"0.8\" stroke=\"#999999\"/>\n", legend_x, legend_y, legend_width, legend_height));
+            for (i, s) in self.series.iter().enumerate() {
+                let y = legend_y + 4.0 + (i as f32 + 0.5) * LEGEND_LINE_HEIGHT;
+                svg.push_str(&format!("<line x1=\"{:.2}\" y1=\"{2:.2}\" x2=\"{:.2}\" y2=\"{2:.2}\" stroke=\"{3}\" stroke-width=\"1.5\"/>\n", legend_x + 6.0, legend_x + 26.0, y, escape(&s.color)));
+                svg.push_str(&format!("<text x=\"{:.2}\" y=\"{:.2}\">{}</text>\n", legend_x + 32.0, y + 4.0, escape(&s.caption)));
+            }
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Render the plot and write it into a file. Like `plot::Plot::save`, it's written into a
+    /// temporary file first, so that an existing file is only replaced when the new one is complete.
+    #[cfg(feature = "std")]
+    pub fn save(&self, path: impl AsRef<Path>) -> FnResult<()> {
+        let path = path.as_ref();
+        let tmp_path = match path.to_str() {
+            Some(s) => tmp_path(s),
+            None => bail!("Path {} is not valid UTF-8.", path.display())
+        };
+        let result = fs::write(&tmp_path, self.render()).and_then(|_| fs::rename(&tmp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        Ok(result?)
+    }
+}
+
+impl<'a> Default for SvgPlot<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The distance between ticks for the range, which is 1, 2 or 5 times a power of ten and
+/// leads to at most max_count ticks.
+fn tick_step(min: f32, max: f32, max_count: usize) -> f32 {
+    let rough = (max - min) / max_count as f32;
+    let magnitude = Float::powf(10.0, Float::floor(Float::log10(rough)));
+    let step = match rough / magnitude {
+        n if n <= 1.0 => 1.0,
+        n if n <= 2.0 => 2.0,
+        n if n <= 5.0 => 5.0,
+        _ => 10.0
+    };
+    step * magnitude
+}
+
+fn ticks(min: f32, max: f32, max_count: usize) -> Vec<f32> {
+    let step = tick_step(min, max, max_count);
+    let first = Float::ceil(min / step) as i64;
+    let last = Float::floor(max / step + 0.001) as i64;
+    (first..=last).map(|i| i as f32 * step).collect()
+}
+
+/// The number of decimals needed to tell the ticks apart.
+fn decimals(min: f32, max: f32, max_count: usize) -> usize {
+    (-Float::floor(Float::log10(tick_step(min, max, max_count)))).max(0.0) as usize
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::svg::*;
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+    use crate::regular_dynamic::RegularDynamicCurve;
+
+    #[test]
+    fn test_ticks() {
+        let labels : Vec<String> = ticks(0.0, 1.0, 10).iter().map(|t| format!("{:.1}", t)).collect();
+        assert_eq!(labels, vec!["0.0", "0.1", "0.2", "0.3", "0.4", "0.5", "0.6", "0.7", "0.8", "0.9", "1.0"]);
+        assert_eq!(ticks(-3.0, 97.0, 10), vec![0.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0]);
+        assert_eq!(ticks(0.0, 0.08, 5).len(), 5);
+        assert_eq!(decimals(0.0, 0.08, 5), 2);
+        assert_eq!(decimals(0.0, 1000.0, 5), 0);
+    }
+
+    #[test]
+    fn test_svg() {
+        let a = IrregularDynamicCurve::<f32, f32>::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 10.0, y: 0.2 }, Tup { x: 20.0, y: 1.0 }]);
+        let b = RegularDynamicCurve::<f32, f32>::new(10.0, 10.0, vec![0.0, 0.6, 1.0]);
+        let mut plot = SvgPlot::new();
+        plot.add_curve(&a, "a < b")
+            .add_curve_with_color(&b, "b", "#ff8000")
+            .set_title("Two curves")
+            .set_x_label("delay (s)");
+
+        let svg = plot.render();
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<polyline").count(), 2);
+        assert!(svg.contains("a &lt; b"));
+        assert!(svg.contains("stroke=\"#ff8000\""));
+        assert!(svg.contains(">probability<"));
+        assert!(!svg.contains("NaN"));
+        // the first curve starts at the bottom left corner of the plot area
+        assert!(svg.contains(&format!("points=\"{:.2},{:.2} ", MARGIN_LEFT, 600.0 - MARGIN_BOTTOM)));
+
+        plot.set_view(SvgView::Density);
+        assert_eq!(plot.lines()[0], vec![(0.0, 0.02), (10.0, 0.02), (10.0, 0.08), (20.0, 0.08)]);
+        let svg = plot.render();
+        assert!(svg.contains(">density<"));
+        assert!(!svg.contains("NaN"));
+
+        // an empty plot still has axes
+        assert!(SvgPlot::new().render().contains("<rect"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_svg_curve_set() {
+        let a = IrregularDynamicCurve::<f32, f32>::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 10.0, y: 0.2 }, Tup { x: 20.0, y: 1.0 }]);
+        let mut set = CurveSet::<f32, IrregularDynamicCurve<f32, f32>>::new();
+        set.add_curve(0.0, a.clone());
+        set.add_curve(60.0, a);
+        let mut plot = SvgPlot::new();
+        plot.add_curve_set(&set, "key");
+        let svg = plot.render();
+        assert_eq!(svg.matches("<polyline").count(), 2);
+        assert!(svg.contains(">key 60<"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_svg_save() {
+        let dir = std::env::temp_dir().join("dystonse-curves-test_svg_save");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plot.svg");
+        std::fs::write(&path, "old").unwrap();
+        SvgPlot::new().set_title("new").save(&path).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains(">new<"));
+        // no temporary files are left
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(SvgPlot::new().save(dir.join("missing").join("plot.svg")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
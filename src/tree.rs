@@ -1,78 +1,858 @@
-use std::fs;
-use std::fs::File;
 use std::io::prelude::*;
-use serde::{Serialize, de::DeserializeOwned};
+use serde::{Serialize, Deserialize, Deserializer, de::DeserializeOwned};
+use std::cell::Cell;
+use std::convert::TryInto;
 
 use crate::FnResult;
+use crate::storage::{Storage, FileSystemStorage};
+use simple_error::bail;
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::str::FromStr;
 
+/// Derive macro for `TreeData`, see the `dystonse-curves-derive` crate for the supported attributes.
+#[cfg(feature = "derive")]
+pub use dystonse_curves_derive::TreeData;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum SerdeFormat {
     Json,
-    MessagePack
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// Concise Binary Object Representation (RFC 7049), a standard format for cross-language exchange.
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// A compact, fast, but Rust-specific format.
+    #[cfg(feature = "bincode")]
+    Bincode,
+    /// The inner format, compressed with the given algorithm. Files saved in this format get
+    /// an additional extension, e.g. `icrv.gz`. See `SerdeFormat::compressed`.
+    Compressed(Compression, Box<SerdeFormat>)
+}
+
+impl SerdeFormat {
+    /// Wrap this format so that the serialized data will be compressed.
+    pub fn compressed(self, compression: Compression) -> Self {
+        SerdeFormat::Compressed(compression, Box::new(self))
+    }
+
+    /// The format without any compression.
+    pub fn uncompressed(&self) -> &SerdeFormat {
+        match self {
+            SerdeFormat::Compressed(_, inner) => inner.uncompressed(),
+            _ => self
+        }
+    }
+
+    /// Call f with a writer that applies the compressions of this format to everything written to it, 
+    /// and the format without any compression.
+    fn write_with<W: Write>(&self, writer: W, f: impl FnOnce(&mut dyn Write, &SerdeFormat) -> FnResult<()>) -> FnResult<()> {
+        match self {
+            SerdeFormat::Compressed(compression, inner) => compression.write_with(writer, |w| inner.write_with(w, f)),
+            _ => f(&mut { writer }, self)
+        }
+    }
+
+    /// Wrap reader into a reader that undoes the compressions of this format.
+    fn decompressed<'a, R: Read + 'a>(&self, reader: R) -> FnResult<Box<dyn Read + 'a>> {
+        match self {
+            SerdeFormat::Compressed(compression, inner) => inner.decompressed(compression.reader(reader)?),
+            _ => Ok(Box::new(reader))
+        }
+    }
+
+    /// The compressions applied by this format, from the innermost to the outermost one.
+    fn compressions(&self) -> Vec<Compression> {
+        match self {
+            SerdeFormat::Compressed(compression, inner) => {
+                let mut compressions = inner.compressions();
+                compressions.push(*compression);
+                compressions
+            },
+            _ => Vec::new()
+        }
+    }
+
+    fn write<T: Serialize, W: Write>(&self, value: &T, writer: W) -> FnResult<()> {
+        match self {
+            #[cfg(feature = "msgpack")]
+            SerdeFormat::MessagePack => rmp_serde::encode::write(&mut { writer }, value)?,
+            SerdeFormat::Json => serde_json::to_writer(writer, value)?,
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor => ciborium::ser::into_writer(value, writer)?,
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode => bincode::serialize_into(writer, value)?,
+            SerdeFormat::Compressed(compression, inner) => compression.write_with(writer, |w| inner.write(value, w))?,
+        };
+        Ok(())
+    }
+
+    fn read<T: DeserializeOwned, R: Read>(&self, reader: R) -> FnResult<T> {
+        Ok(match self {
+            #[cfg(feature = "msgpack")]
+            SerdeFormat::MessagePack => rmp_serde::from_read(reader)?,
+            SerdeFormat::Json => serde_json::from_reader(reader)?,
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor => ciborium::de::from_reader(reader)?,
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode => bincode::deserialize_from(reader)?,
+            SerdeFormat::Compressed(compression, inner) => inner.read(compression.reader(reader)?)?,
+        })
+    }
+}
+
+/// The file extensions for the given compressions (each including the leading dot).
+fn compression_ext(compressions: &[Compression]) -> String {
+    compressions.iter().map(|c| format!(".{}", c.get_ext())).collect()
+}
+
+/// Compression algorithms which can be used with `SerdeFormat::Compressed`. 
+/// Each of them is only available if the cargo feature of the same name is enabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// All compression algorithms that are available.
+    pub const ALL : &'static [Compression] = &[
+        #[cfg(feature = "gzip")]
+        Compression::Gzip,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+    ];
+
+    /// Get the file extention (without leading dot) for this compression.
+    pub fn get_ext(self) -> &'static str {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => "gz",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// Call f with a writer that compresses everything written to it into writer.
+    #[allow(unused_variables)] // if no compression feature is enabled
+    fn write_with<W, F>(self, writer: W, f: F) -> FnResult<()> where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> FnResult<()>
+    {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+                f(&mut encoder)?;
+                encoder.finish()?;
+                Ok(())
+            },
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, 0)?;
+                f(&mut encoder)?;
+                encoder.finish()?;
+                Ok(())
+            },
+        }
+    }
+
+    /// Wrap reader into a reader that decompresses its data.
+    #[allow(unused_variables)] // if no compression feature is enabled
+    fn reader<'a, R: Read + 'a>(self, reader: R) -> std::io::Result<Box<dyn Read + 'a>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(reader))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(zstd::Decoder::new(reader)?)),
+        }
+    }
 }
 
 /// Trait for every object in a tree structure.
 pub trait NodeData {
-    /// Use serde to save this object (along with all its children, if present) into a single file. This function is implemented by a blanket impl.
-    fn save_to_file(&self, dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<()>;
-    /// Use serde to load an object of this type (along with all its children, if present) from a single file. This function is implemented by a blanket impl.
-    fn load_from_file(dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<Box<Self>>;
+    /// Use serde to write this object (along with all its children, if present) to the writer. 
+    /// The data is preceded by a small header with the `LeafData::VERSION` of this type.
+    /// This function is implemented by a blanket impl.
+    fn save_to_writer(&self, writer: &mut impl Write, format: &SerdeFormat) -> FnResult<()>;
+    /// Use serde to read an object of this type (along with all its children, if present) from the reader,
+    /// which must not contain any other data. Data of older versions, and data that has been saved
+    /// before versions were introduced, is passed to `LeafData::migrate`.
+    /// This function is implemented by a blanket impl.
+    fn load_from_reader(reader: impl Read, format: &SerdeFormat) -> FnResult<Box<Self>>;
+    /// Use serde to save this object (along with all its children, if present) into a single file of the storage. 
+    /// The file extension is determined by `LeafData::get_ext`. This function is implemented by a blanket impl.
+    fn save_to_storage(&self, storage: &dyn Storage, dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<()>;
+    /// Use serde to load an object of this type (along with all its children, if present) from a single file of the storage. 
+    /// The file extension is determined by `LeafData::get_ext`. If the file exists with several compressions, 
+    /// the one requested by format is preferred, then the uncompressed one, then the first one of `Compression::ALL`.
+    /// This function is implemented by a blanket impl.
+    fn load_from_storage(storage: &dyn Storage, dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<Box<Self>>;
+    /// Like `save_to_storage`, using a `FileSystemStorage`.
+    fn save_to_file(&self, dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<()> {
+        self.save_to_storage(&FileSystemStorage, dir_name, file_name, format)
+    }
+    /// Like `load_from_storage`, using a `FileSystemStorage`.
+    fn load_from_file(dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<Box<Self>> {
+        Self::load_from_storage(&FileSystemStorage, dir_name, file_name, format)
+    }
 }
 
 pub trait LeafData {
+    /// The version of the serialized layout of this type, which is saved along with the data.
+    /// It has to be increased whenever the layout changes, including changes of the types
+    /// contained in this one. Data of older versions can then be converted by `migrate`.
+    const VERSION : u32 = 1;
+
+    /// Deserialize data that has been saved with an older version (or, with version 0, before
+    /// versions were saved at all). The default implementation assumes that the layout of 
+    /// version 0 equals the layout of version 1, and rejects all other versions.
+    fn migrate<'de, D: Deserializer<'de>>(version: u32, deserializer: D) -> Result<Self, D::Error> where
+    Self: Deserialize<'de>
+    {
+        if version == 0 && Self::VERSION == 1 {
+            return Self::deserialize(deserializer);
+        }
+        Err(serde::de::Error::custom(format!("unsupported version {} of {}, expected {}", version, std::any::type_name::<Self>(), Self::VERSION)))
+    }
+
     /// Get the file extention (without leading dot) for this type, possibly depending on the given format.
+    /// Compression is not reflected here, `NodeData` adds another extension for it.
+    /// Types which are saved into the same directories should override this with an extension
+    /// of their own for each binary format (like `icrv` and `icrv.cbor`), so that `LazyTree` can tell them apart.
     fn get_ext(format: &SerdeFormat) -> &str {
         match format {
             SerdeFormat::Json => "json",
-            SerdeFormat::MessagePack => "mpack"
+            #[cfg(feature = "msgpack")]
+            SerdeFormat::MessagePack => "mpack",
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor => "cbor",
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode => "bincode",
+            SerdeFormat::Compressed(_, inner) => Self::get_ext(inner)
         }
     }
 }
 
 /// Trait for every object in a tree structure that has children, i.e. everything except leaves.
 pub trait TreeData : Sized {
+    /// The name by which this type can be selected in the leaves of `save_tree` and `load_tree`.
+    /// The containers from the standard library use the same name for all their key and value
+    /// types, e.g. `"HashMap"` selects every level of a `HashMap<String, HashMap<u64, V>>`.
+    /// To select only some of them, wrap them in a struct with a name of its own (see the
+    /// `TreeData` derive macro). As an empty container saved as a directory leaves no trace in 
+    /// the storage, it is loaded as empty as well, but it is lost if it's the child of another one.
+    const NAME : &'static str;
+
     /// Save this objects and its children. If Self::NAME is among the supplied leaves, it will be 
     /// saved into a single file. Otherwise, it will create a directory structure for its children,
     /// which might saved as files or more levels of subdirectories.
-    fn save_tree(&self, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()>;
-    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self>;
+    /// The own_name is encoded with `encode_name` before it is used as a file or directory name.
+    fn save_tree_to_storage(&self, storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()>;
+    /// Load an object and its children from the files and directories created by `save_tree_to_storage`.
+    fn load_tree_from_storage(storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self>;
+
+    /// Like `save_tree_to_storage`, using a `FileSystemStorage`.
+    fn save_tree(&self, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
+        self.save_tree_to_storage(&FileSystemStorage, dir_name, own_name, format, leaves)
+    }
+    /// Like `load_tree_from_storage`, using a `FileSystemStorage`.
+    fn load_tree(dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self> {
+        Self::load_tree_from_storage(&FileSystemStorage, dir_name, own_name, format, leaves)
+    }
 }
 
 impl<'a, T> NodeData for T
-where T: Serialize + DeserializeOwned
+where T: Serialize + DeserializeOwned + LeafData
 {
-    fn save_to_file(&self, dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<()> {
-        let serialized_bin = match format {
-            SerdeFormat::MessagePack => rmp_serde::to_vec(self).unwrap(),
-            SerdeFormat::Json => serde_json::to_vec(self).unwrap(),
-        };
-        fs::create_dir_all(&dir_name)?;
-        let file_ext = "exp"; // Self::get_ext(format);
-        let file_path = format!("{}/{}.{}", dir_name, file_name, file_ext);
-        let mut file = match File::create(&file_path) {
-            Err(why) => panic!("couldn't create file {}: {}", file_path, why),
-            Ok(file) => file,
-        };
-        match file.write_all(&serialized_bin) {
-            Err(why) => panic!("couldn't write: {}", why),
-            Ok(_) => println!("successfully wrote."),
+    fn save_to_writer(&self, writer: &mut impl Write, format: &SerdeFormat) -> FnResult<()> {
+        format.write_with(writer, |writer, format| {
+            writer.write_all(VERSION_MAGIC)?;
+            writer.write_all(&Self::VERSION.to_le_bytes())?;
+            format.write(self, writer)
+        })
+    }
+
+    fn load_from_reader(reader: impl Read, format: &SerdeFormat) -> FnResult<Box<Self>> {
+        let mut reader = format.decompressed(reader)?;
+        let format = format.uncompressed();
+        let mut header = Vec::with_capacity(VERSION_HEADER_LEN);
+        (&mut reader).take(VERSION_HEADER_LEN as u64).read_to_end(&mut header)?;
+        if header.len() == VERSION_HEADER_LEN && header.starts_with(VERSION_MAGIC) {
+            let version = u32::from_le_bytes(header[VERSION_MAGIC.len()..].try_into()?);
+            Ok(Box::new(read_version(format, reader, version)?))
+        } else {
+            // the data is from before versions were introduced, and starts with the bytes that have been read already
+            Ok(Box::new(read_version(format, (&header[..]).chain(reader), 0)?))
         }
-    
+    }
+
+    fn save_to_storage(&self, storage: &dyn Storage, dir_name: &str, file_name: &str, format: &SerdeFormat) -> FnResult<()> {
+        let file_ext = Self::get_ext(format);
+        let full_name = format!("{}.{}{}", file_name, file_ext, compression_ext(&format.compressions()));
+        storage.write_with(dir_name, &full_name, &mut |mut writer| self.save_to_writer(&mut writer, format))
+            .map_err(|why| format!("couldn't write file {}/{}: {}", dir_name, full_name, why))?;
         Ok(())
     }
 
-    fn load_from_file(dir_name: &str, file_name: &str, format: &SerdeFormat)  -> FnResult<Box<Self>> {
-        let file_ext = "exp"; // Self::get_ext(format);
-        let file_path = format!("{}/{}.{}", dir_name, file_name, file_ext);
+    fn load_from_storage(storage: &dyn Storage, dir_name: &str, file_name: &str, format: &SerdeFormat)  -> FnResult<Box<Self>> {
+        let file_ext = Self::get_ext(format);
+        let base_name = format!("{}.{}", file_name, file_ext);
+
+        // If the file does not exist in the requested compression, look for other ones.
+        // The compression is detected by the file extension, and not by looking at the data,
+        // which would mean to read the data in advance instead of streaming it. Also, uncompressed
+        // data might start with the magic bytes of a compression, e.g. in bincode, a vector of 
+        // 35615 elements starts with the bytes which identify gzip data.
+        let requested = format.compressions();
+        let candidates = std::iter::once(requested.clone())
+            .chain(std::iter::once(Vec::new()))
+            .chain(Compression::ALL.iter().map(|c| vec![*c]));
+        let mut existing = None;
+        for compressions in candidates {
+            let name = format!("{}{}", base_name, compression_ext(&compressions));
+            if storage.exists(dir_name, &name)? {
+                existing = Some((name, compressions));
+                break;
+            }
+        }
+        let (full_name, compressions) = existing.unwrap_or_else(|| (format!("{}{}", base_name, compression_ext(&requested)), requested));
+        let file_format = compressions.iter().fold(format.uncompressed().clone(), |f, c| f.compressed(*c));
         
-        let mut f = File::open(&file_path).expect(&format!("Could not open {}", file_path));
-        let mut buffer = Vec::new();
-        f.read_to_end(&mut buffer)?;
+        let reader = storage.reader(dir_name, &full_name).map_err(|why| format!("couldn't open file {}/{}: {}", dir_name, full_name, why))?;
+        let parsed = Self::load_from_reader(reader, &file_format)
+            .map_err(|why| format!("couldn't read file {}/{}: {}", dir_name, full_name, why))?;
+
+       Ok(parsed)
+    }
+}
+
+/// Identifies data written by `NodeData::save_to_writer`. It is followed by the `LeafData::VERSION`
+/// of the data as a little-endian u32, and then by the data itself. JSON, MessagePack and CBOR
+/// data from before versions were introduced can't start with these bytes, and the layout of
+/// the curves in bincode doesn't either.
+const VERSION_MAGIC : &[u8; 4] = b"DCRV";
+/// The length of `VERSION_MAGIC` and the version.
+const VERSION_HEADER_LEN : usize = 8;
+
+thread_local! {
+    /// The version of the data that is being deserialized by `read_version`.
+    static LOADING_VERSION : Cell<u32> = const { Cell::new(0) };
+}
+
+/// Read a T that has been saved with the given version, which is passed to `LeafData::migrate` if it's not the current one.
+fn read_version<T: DeserializeOwned + LeafData>(format: &SerdeFormat, reader: impl Read, version: u32) -> FnResult<T> {
+    // Not all formats can deserialize with a `DeserializeSeed`, so the version is passed to `AtVersion` besides serde.
+    let previous = LOADING_VERSION.with(|v| v.replace(version));
+    let result = format.read::<AtVersion<T>, _>(reader);
+    LOADING_VERSION.with(|v| v.set(previous));
+    Ok(result?.0)
+}
+
+/// Deserializes a T that has been saved with the version in `LOADING_VERSION`.
+struct AtVersion<T>(T);
+
+impl<'de, T: Deserialize<'de> + LeafData> Deserialize<'de> for AtVersion<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = LOADING_VERSION.with(|v| v.get());
+        if version == T::VERSION {
+            Ok(AtVersion(T::deserialize(deserializer)?))
+        } else {
+            Ok(AtVersion(T::migrate(version, deserializer)?))
+        }
+    }
+}
+
+impl<K, V> TreeData for HashMap<K, V> where
+K: Display + FromStr + Eq + Hash,
+K::Err: Error + 'static,
+V: TreeData + LeafData,
+HashMap<K, V>: NodeData
+{
+    const NAME : &'static str = "HashMap";
+
+    /// Save each value as a child named after its key. If Self::NAME is among the leaves, 
+    /// the whole map is saved into a single file instead.
+    fn save_tree_to_storage(&self, storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
+        if leaves.contains(&Self::NAME) {
+            return self.save_to_storage(storage, dir_name, &encode_name(own_name), format);
+        }
+        save_children(self.iter(), storage, dir_name, own_name, format, leaves)
+    }
+
+    fn load_tree_from_storage(storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self> {
+        if leaves.contains(&Self::NAME) {
+            return Ok(*Self::load_from_storage(storage, dir_name, &encode_name(own_name), format)?);
+        }
+        Ok(load_children(storage, dir_name, own_name, format, leaves)?.into_iter().collect())
+    }
+}
+
+impl<K, V> LeafData for HashMap<K, V> {}
+
+impl<K, V> TreeData for BTreeMap<K, V> where
+K: Display + FromStr + Ord,
+K::Err: Error + 'static,
+V: TreeData + LeafData,
+BTreeMap<K, V>: NodeData
+{
+    const NAME : &'static str = "BTreeMap";
+
+    /// Save each value as a child named after its key. If Self::NAME is among the leaves, 
+    /// the whole map is saved into a single file instead.
+    fn save_tree_to_storage(&self, storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
+        if leaves.contains(&Self::NAME) {
+            return self.save_to_storage(storage, dir_name, &encode_name(own_name), format);
+        }
+        save_children(self.iter(), storage, dir_name, own_name, format, leaves)
+    }
+
+    fn load_tree_from_storage(storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self> {
+        if leaves.contains(&Self::NAME) {
+            return Ok(*Self::load_from_storage(storage, dir_name, &encode_name(own_name), format)?);
+        }
+        Ok(load_children(storage, dir_name, own_name, format, leaves)?.into_iter().collect())
+    }
+}
+
+impl<K, V> LeafData for BTreeMap<K, V> {}
+
+impl<V> TreeData for Vec<V> where
+V: TreeData + LeafData,
+Vec<V>: NodeData
+{
+    const NAME : &'static str = "Vec";
+
+    /// Save each element as a child named after its index. If Self::NAME is among the leaves, 
+    /// the whole vector is saved into a single file instead.
+    fn save_tree_to_storage(&self, storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> {
+        if leaves.contains(&Self::NAME) {
+            return self.save_to_storage(storage, dir_name, &encode_name(own_name), format);
+        }
+        save_children(self.iter().enumerate(), storage, dir_name, own_name, format, leaves)
+    }
 
-        let parsed = match format {
-            SerdeFormat::MessagePack => rmp_serde::from_read_ref::<_, Self>(&buffer)?,
-            SerdeFormat::Json => serde_json::from_slice(&buffer)?,
+    fn load_tree_from_storage(storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Self> {
+        if leaves.contains(&Self::NAME) {
+            return Ok(*Self::load_from_storage(storage, dir_name, &encode_name(own_name), format)?);
+        }
+        let mut children : Vec<(usize, V)> = load_children(storage, dir_name, own_name, format, leaves)?;
+        children.sort_by_key(|(i, _)| *i);
+        // different names might be parsed as the same index, e.g. "1" and "01"
+        if let Some(pair) = children.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            bail!("Duplicate element {} in {}/{}", pair[0].0, dir_name, encode_name(own_name));
+        }
+        if let Some((expected, _)) = children.iter().enumerate().find(|(expected, (i, _))| expected != i) {
+            bail!("Missing element {} in {}/{}", expected, dir_name, encode_name(own_name));
+        }
+        Ok(children.into_iter().map(|(_, child)| child).collect())
+    }
+}
+
+impl<V> LeafData for Vec<V> {}
+
+/// Save each child into the directory of the parent, using the key as the child's name.
+fn save_children<'a, K, V, I>(children: I, storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<()> where
+I: Iterator<Item = (K, &'a V)>,
+K: Display,
+V: TreeData + 'a
+{
+    let sub_dir_name = format!("{}/{}", dir_name, encode_name(own_name));
+    for (key, child) in children {
+        child.save_tree_to_storage(storage, &sub_dir_name, &key.to_string(), format, leaves)?;
+    }
+    Ok(())
+}
+
+/// Load all children that have been saved by `save_children`. Depending on the leaves, 
+/// these are either files (with the extension of V) or directories.
+fn load_children<K, V>(storage: &dyn Storage, dir_name: &str, own_name: &str, format: &SerdeFormat, leaves: &Vec<&str>) -> FnResult<Vec<(K, V)>> where
+K: FromStr,
+K::Err: Error + 'static,
+V: TreeData + LeafData
+{
+    let sub_dir_name = format!("{}/{}", dir_name, encode_name(own_name));
+    let collapsed = leaves.contains(&V::NAME);
+    let ext = V::get_ext(format);
+    // ignore everything that has not been written by save_children. The same file might be 
+    // present with different compressions, but is loaded only once, in the compression chosen
+    // by `NodeData::load_from_storage`.
+    let encoded_keys : BTreeSet<String> = if collapsed {
+        storage.list_files(&sub_dir_name)?.iter().filter_map(|f| strip_ext(f, ext)).map(|k| k.to_string()).collect()
+    } else {
+        storage.list_dirs(&sub_dir_name)?.into_iter().collect()
+    };
+    let mut children = Vec::new();
+    for encoded_key in encoded_keys {
+        let key_string = decode_name(&encoded_key)?;
+        let key = match key_string.parse() {
+            Ok(k) => k,
+            Err(e) => bail!("Could not parse key of {}/{}: {}", sub_dir_name, encoded_key, e)
         };
+        children.push((key, V::load_tree_from_storage(storage, &sub_dir_name, &key_string, format, leaves)?));
+    }
+    Ok(children)
+}
+
+/// If file_name ends with the given extension, possibly followed by the extensions of one or 
+/// more compressions, return the part before the extension.
+pub fn strip_ext<'a>(file_name: &'a str, ext: &str) -> Option<&'a str> {
+    let mut name = file_name;
+    loop {
+        if let Some(base) = name.strip_suffix(ext).and_then(|n| n.strip_suffix('.')) {
+            return Some(base);
+        }
+        name = Compression::ALL.iter()
+            .find_map(|c| name.strip_suffix(c.get_ext()).and_then(|n| n.strip_suffix('.')))?;
+    }
+}
+
+/// Encode a name (or key) so that it can safely be used as a file or directory name on any
+/// file system. All characters except ASCII letters, digits, `-`, `_` and `.` are 
+/// percent-encoded as UTF-8 bytes, so names containing spaces, umlauts or slashes 
+/// survive the round trip through `decode_name` unchanged. A leading `.` is encoded as well,
+/// so that `.` and `..` can't refer to other directories and no hidden files are created,
+/// and the empty name is encoded as `%`, because an empty file name would refer to the 
+/// parent directory.
+pub fn encode_name(name: &str) -> String {
+    if name.is_empty() {
+        return String::from(EMPTY_NAME);
+    }
+    let mut encoded = String::with_capacity(name.len());
+    for (i, byte) in name.bytes().enumerate() {
+        match byte {
+            b'.' if i == 0 => encoded.push_str("%2E"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    return encoded;
+}
+
+/// The encoding of the empty name, which can't be the result of encoding any other name.
+const EMPTY_NAME : &str = "%";
 
-       Ok(Box::new(parsed))
+/// Decode a name that was encoded with `encode_name`.
+pub fn decode_name(encoded: &str) -> FnResult<String> {
+    if encoded == EMPTY_NAME {
+        return Ok(String::new());
     }
-}
\ No newline at end of file
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = encoded.get(i + 1 .. i + 3).ok_or_else(|| format!("Truncated escape sequence in {}", encoded))?;
+            decoded.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    return Ok(String::from_utf8(decoded)?);
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use crate::tree::{encode_name, decode_name, strip_ext, NodeData, LeafData, TreeData, SerdeFormat};
+    use crate::storage::{Storage, MemoryStorage};
+    use serde::{Serialize, Deserialize, Deserializer};
+    use crate::irregular_dynamic::{IrregularDynamicCurve, Tup};
+    use crate::curve_set::CurveSet;
+    use crate::Curve;
+    use std::collections::{HashMap, BTreeMap};
+
+    #[test]
+    fn test_name_encoding() {
+        for name in &["Linie_4", "Bremer Straßenbahn AG", "curve_-72.5", "a/b\\c%d", "", ".", "..", ".hidden", "%"] {
+            let encoded = encode_name(name);
+            assert!(!encoded.is_empty());
+            assert!(!encoded.starts_with('.'));
+            assert!(!encoded.contains(' '));
+            assert!(!encoded.contains('/'));
+            assert_eq!(&decode_name(&encoded).unwrap(), name);
+        }
+        assert_eq!(encode_name(""), "%");
+        assert_eq!(encode_name("."), "%2E");
+        assert_eq!(encode_name(".."), "%2E.");
+        assert_eq!(encode_name("curve_1.5"), "curve_1.5");
+        assert_eq!(encode_name("Bremer Straßenbahn AG"), "Bremer%20Stra%C3%9Fenbahn%20AG");
+        assert!(decode_name("broken%2").is_err());
+    }
+
+    #[test]
+    fn test_strip_ext() {
+        assert_eq!(strip_ext("curve_1.5.icrv", "icrv"), Some("curve_1.5"));
+        assert_eq!(strip_ext("curve_1.5.rcrv", "icrv"), None);
+        assert_eq!(strip_ext("curve_1.5.icrv.tmp123", "icrv"), None);
+        #[cfg(feature = "gzip")]
+        assert_eq!(strip_ext("curve_1.5.icrv.gz", "icrv"), Some("curve_1.5"));
+    }
+
+    #[test]
+    fn test_save_to_writer() {
+        let curve = IrregularDynamicCurve::<f32, f32>::new(vec![
+            Tup { x: 0.0, y: 0.0 },
+            Tup { x: 5.0, y: 0.3 },
+            Tup { x: 10.0, y: 1.0 },
+        ]);
+        let mut formats = vec![SerdeFormat::Json, SerdeFormat::MessagePack];
+        for compression in crate::tree::Compression::ALL {
+            formats.push(SerdeFormat::MessagePack.compressed(*compression));
+        }
+        for format in &formats {
+            let mut buffer = Vec::new();
+            curve.save_to_writer(&mut buffer, format).unwrap();
+            let loaded = IrregularDynamicCurve::<f32, f32>::load_from_reader(std::io::Cursor::new(buffer), format).unwrap();
+            assert_eq!(loaded.get_x_values(), curve.get_x_values());
+        }
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_compression_by_extension() {
+        // legacy data without a version header, which starts with the magic bytes of gzip
+        let values = vec![7u8; 0x8b1f];
+        let mut buffer = Vec::new();
+        SerdeFormat::Bincode.write(&values, &mut buffer).unwrap();
+        assert!(buffer.starts_with(&[0x1f, 0x8b]));
+
+        let storage = MemoryStorage::new();
+        storage.write("data", "values.bincode", &buffer).unwrap();
+        let loaded = Vec::<u8>::load_from_storage(&storage, "data", "values", &SerdeFormat::Bincode).unwrap();
+        assert_eq!(*loaded, values);
+    }
+
+    /// The layout of TestPoint in version 1.
+    #[derive(Serialize, Deserialize)]
+    struct OldTestPoint {
+        x: f32,
+    }
+
+    impl LeafData for OldTestPoint {}
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct TestPoint {
+        x: f32,
+        y: f32,
+    }
+
+    impl LeafData for TestPoint {
+        const VERSION : u32 = 2;
+
+        fn migrate<'de, D: Deserializer<'de>>(version: u32, deserializer: D) -> Result<Self, D::Error> {
+            match version {
+                0 | 1 => {
+                    let old = OldTestPoint::deserialize(deserializer)?;
+                    Ok(TestPoint { x: old.x, y: 0.0 })
+                },
+                _ => Err(serde::de::Error::custom(format!("unsupported version {}", version)))
+            }
+        }
+    }
+
+    #[test]
+    fn test_versioning() {
+        let curve = IrregularDynamicCurve::<f32, f32>::new(vec![
+            Tup { x: 0.0, y: 0.0 },
+            Tup { x: 10.0, y: 1.0 },
+        ]);
+        let mut formats = vec![
+            SerdeFormat::Json,
+            SerdeFormat::MessagePack,
+            #[cfg(feature = "cbor")]
+            SerdeFormat::Cbor,
+            #[cfg(feature = "bincode")]
+            SerdeFormat::Bincode,
+        ];
+        for compression in crate::tree::Compression::ALL {
+            formats.push(SerdeFormat::MessagePack.compressed(*compression));
+        }
+        for format in &formats {
+            // data from before versions were introduced
+            let mut buffer = Vec::new();
+            format.write(&curve, &mut buffer).unwrap();
+            let loaded = IrregularDynamicCurve::<f32, f32>::load_from_reader(&buffer[..], format).unwrap();
+            assert_eq!(loaded.get_x_values(), curve.get_x_values());
+            let mut buffer = Vec::new();
+            format.write(&OldTestPoint { x: 1.0 }, &mut buffer).unwrap();
+            assert_eq!(*TestPoint::load_from_reader(&buffer[..], format).unwrap(), TestPoint { x: 1.0, y: 0.0 });
+
+            // data from an older version is migrated
+            let mut buffer = Vec::new();
+            OldTestPoint { x: 2.0 }.save_to_writer(&mut buffer, format).unwrap();
+            assert_eq!(*TestPoint::load_from_reader(&buffer[..], format).unwrap(), TestPoint { x: 2.0, y: 0.0 });
+
+            // the current version is loaded as it is
+            let mut buffer = Vec::new();
+            TestPoint { x: 3.0, y: 4.0 }.save_to_writer(&mut buffer, format).unwrap();
+            assert_eq!(*TestPoint::load_from_reader(&buffer[..], format).unwrap(), TestPoint { x: 3.0, y: 4.0 });
+
+            // data from a newer version can't be loaded
+            let error = OldTestPoint::load_from_reader(&buffer[..], format).err().unwrap();
+            assert!(error.to_string().contains("unsupported version 2"), "{}", error);
+        }
+
+        let mut buffer = Vec::new();
+        curve.save_to_writer(&mut buffer, &SerdeFormat::Json).unwrap();
+        assert!(buffer.starts_with(b"DCRV\x01\x00\x00\x00{"));
+    }
+
+    #[test]
+    fn test_save_to_file_errors() {
+        let curve = IrregularDynamicCurve::<f32, f32>::new(vec![
+            Tup { x: 0.0, y: 0.0 },
+            Tup { x: 10.0, y: 1.0 },
+        ]);
+        let dir = std::env::temp_dir().join("dystonse-curves-test_save_to_file_errors");
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir_name = dir.to_str().unwrap();
+
+        curve.save_to_file(dir_name, "curve", &SerdeFormat::MessagePack).unwrap();
+        // only the final file is left, no temporary files
+        let files : Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(files, vec!["curve.icrv"]);
+
+        // a directory can't be created below a file, which must be reported instead of panicking
+        let bad_dir_name = format!("{}/curve.icrv/sub", dir_name);
+        let error = curve.save_to_file(&bad_dir_name, "curve", &SerdeFormat::MessagePack).unwrap_err();
+        assert!(error.to_string().contains(&bad_dir_name));
+
+        let error = IrregularDynamicCurve::<f32, f32>::load_from_file(dir_name, "missing", &SerdeFormat::MessagePack).unwrap_err();
+        assert!(error.to_string().contains("missing.icrv"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    type TestSet = CurveSet<f32, IrregularDynamicCurve<f32, f32>>;
+
+    fn test_curve_set(offset: f32) -> TestSet {
+        let mut curve_set = TestSet::new();
+        for key in &[0.0, 10.0] {
+            curve_set.add_curve(*key, IrregularDynamicCurve::new(vec![
+                Tup { x: *key + offset, y: 0.0 },
+                Tup { x: *key + offset + 100.0, y: 1.0 },
+            ]));
+        }
+        curve_set
+    }
+
+    type TestRoutes = HashMap::<String, BTreeMap<u64, Vec<TestSet>>>;
+
+    fn test_routes() -> TestRoutes {
+        // route -> trip -> stop pair -> curve set
+        let mut routes = TestRoutes::new();
+        // the last three names must not be used as they are, which would write into other directories
+        for (r, route) in ["Linie 4", "Linie 10/N10", "Straßenbahn", "", ".", ".."].iter().enumerate() {
+            let mut trips = BTreeMap::new();
+            for trip in 0..3 {
+                let stop_pairs = (0..2).map(|s| test_curve_set((r * 100 + trip * 10 + s) as f32)).collect();
+                trips.insert(trip as u64 * 1000, stop_pairs);
+            }
+            routes.insert(route.to_string(), trips);
+        }
+        routes
+    }
+
+    fn assert_same_routes(routes: &TestRoutes, loaded: &TestRoutes) {
+        assert_eq!(loaded.len(), routes.len());
+        for (route, trips) in routes {
+            let loaded_trips = &loaded[route];
+            assert_eq!(loaded_trips.keys().collect::<Vec<_>>(), trips.keys().collect::<Vec<_>>());
+            for (trip, stop_pairs) in trips {
+                let loaded_stop_pairs = &loaded_trips[trip];
+                assert_eq!(loaded_stop_pairs.len(), stop_pairs.len());
+                for (a, b) in stop_pairs.iter().zip(loaded_stop_pairs.iter()) {
+                    assert_eq!(a.min_x(), b.min_x());
+                    assert_eq!(a.curves[0].1.get_x_values(), b.curves[0].1.get_x_values());
+                }
+            }
+        }
+    }
+
+    const LEAVES_VARIANTS : [&[&str]; 5] = [&[], &[TestSet::NAME], &["Vec"], &["BTreeMap"], &["HashMap"]];
+
+    #[test]
+    fn test_save_and_load_empty_tree() {
+        let dir = std::env::temp_dir().join("dystonse-curves-test_save_and_load_empty_tree");
+        let dir_name = dir.to_str().unwrap();
+
+        for leaves in LEAVES_VARIANTS.iter() {
+            let leaves = leaves.to_vec();
+            let _ = std::fs::remove_dir_all(&dir);
+            TestRoutes::new().save_tree(dir_name, "routes", &SerdeFormat::MessagePack, &leaves).unwrap();
+            assert!(TestRoutes::load_tree(dir_name, "routes", &SerdeFormat::MessagePack, &leaves).unwrap().is_empty());
+            BTreeMap::<u64, TestSet>::new().save_tree(dir_name, "trips", &SerdeFormat::MessagePack, &leaves).unwrap();
+            assert!(BTreeMap::<u64, TestSet>::load_tree(dir_name, "trips", &SerdeFormat::MessagePack, &leaves).unwrap().is_empty());
+            Vec::<TestSet>::new().save_tree(dir_name, "stop_pairs", &SerdeFormat::MessagePack, &leaves).unwrap();
+            assert!(Vec::<TestSet>::load_tree(dir_name, "stop_pairs", &SerdeFormat::MessagePack, &leaves).unwrap().is_empty());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_vec_with_missing_element() {
+        let storage = MemoryStorage::new();
+        let stop_pairs = vec![test_curve_set(0.0), test_curve_set(1.0), test_curve_set(2.0)];
+        stop_pairs.save_tree_to_storage(&storage, "data", "stop_pairs", &SerdeFormat::MessagePack, &vec![TestSet::NAME]).unwrap();
+        let partial = MemoryStorage::new();
+        for index in &["0", "2"] {
+            let file_name = format!("{}.crvs", index);
+            partial.write("data/stop_pairs", &file_name, &storage.read("data/stop_pairs", &file_name).unwrap()).unwrap();
+        }
+        let error = Vec::<TestSet>::load_tree_from_storage(&partial, "data", "stop_pairs", &SerdeFormat::MessagePack, &vec![TestSet::NAME]).unwrap_err();
+        assert_eq!(error.to_string(), "Missing element 1 in data/stop_pairs");
+    }
+
+    #[test]
+    fn test_save_and_load_nested_tree() {
+        let routes = test_routes();
+        let dir = std::env::temp_dir().join("dystonse-curves-test_save_and_load_nested_tree");
+        let dir_name = dir.to_str().unwrap();
+
+        for leaves in LEAVES_VARIANTS.iter() {
+            let leaves = leaves.to_vec();
+            let _ = std::fs::remove_dir_all(&dir);
+            routes.save_tree(dir_name, "routes", &SerdeFormat::MessagePack, &leaves).unwrap();
+            // nothing has been written outside of the routes
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                assert!(entry.unwrap().file_name().to_str().unwrap().starts_with("routes"));
+            }
+            let loaded = TestRoutes::load_tree(dir_name, "routes", &SerdeFormat::MessagePack, &leaves).unwrap();
+            assert_same_routes(&routes, &loaded);
+        }
+
+        assert!(dir.join("routes.mpack").is_file());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_and_load_tree_to_storage() {
+        let routes = test_routes();
+        #[allow(unused_mut)] // only extended if the sqlite feature is enabled
+        let mut storages : Vec<Box<dyn Fn() -> Box<dyn Storage>>> = vec![Box::new(|| Box::new(MemoryStorage::new()))];
+        #[cfg(feature = "sqlite")]
+        storages.push(Box::new(|| Box::new(crate::storage::SqliteStorage::from_connection(rusqlite::Connection::open_in_memory().unwrap()).unwrap())));
+
+        for new_storage in &storages {
+            for leaves in LEAVES_VARIANTS.iter() {
+                let leaves = leaves.to_vec();
+                let storage = new_storage();
+                routes.save_tree_to_storage(storage.as_ref(), "data", "routes", &SerdeFormat::MessagePack, &leaves).unwrap();
+                let loaded = TestRoutes::load_tree_from_storage(storage.as_ref(), "data", "routes", &SerdeFormat::MessagePack, &leaves).unwrap();
+                assert_same_routes(&routes, &loaded);
+            }
+        }
+
+        // nothing has been written to the file system
+        let storage = MemoryStorage::new();
+        routes.save_tree_to_storage(&storage, "data", "routes", &SerdeFormat::MessagePack, &vec![]).unwrap();
+        assert_eq!(storage.len(), 6 * 3 * 2 * 2);
+        assert!(!std::path::Path::new("data").exists());
+    }
+}
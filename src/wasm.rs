@@ -0,0 +1,148 @@
+//! Bindings for JavaScript, e.g. to decode and evaluate curves in the browser.
+//! Build them with `cargo rustc --lib --target wasm32-unknown-unknown --no-default-features --features wasm --crate-type cdylib`
+//! and `wasm-bindgen`, see README.md.
+//! All numbers are f32, and all errors are thrown as JavaScript `Error`s.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{Curve, QUANTILES};
+use crate::curve_set::CurveSet;
+use crate::irregular_dynamic::IrregularDynamicCurve;
+
+fn check_y(y: f32) -> Result<(), JsError> {
+    if !(0.0..=1.0).contains(&y) {
+        return Err(JsError::new(&format!("y must be between 0 and 1, but is {}", y)));
+    }
+    Ok(())
+}
+
+/// A curve, as decoded from the compact format of `IrregularDynamicCurve::serialize_compact`.
+#[wasm_bindgen(js_name = Curve)]
+#[derive(Clone)]
+pub struct WasmCurve {
+    curve: IrregularDynamicCurve<f32, f32>
+}
+
+#[wasm_bindgen(js_class = Curve)]
+impl WasmCurve {
+    #[wasm_bindgen(js_name = deserializeCompact)]
+    pub fn deserialize_compact(bytes: &[u8]) -> Result<WasmCurve, JsError> {
+        let curve = IrregularDynamicCurve::try_deserialize_compact(bytes).map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(Self { curve })
+    }
+
+    #[wasm_bindgen(js_name = minX)]
+    pub fn min_x(&self) -> f32 {
+        self.curve.min_x()
+    }
+
+    #[wasm_bindgen(js_name = maxX)]
+    pub fn max_x(&self) -> f32 {
+        self.curve.max_x()
+    }
+
+    #[wasm_bindgen(js_name = yAtX)]
+    pub fn y_at_x(&self, x: f32) -> f32 {
+        self.curve.y_at_x(x)
+    }
+
+    #[wasm_bindgen(js_name = xAtY)]
+    pub fn x_at_y(&self, y: f32) -> Result<f32, JsError> {
+        check_y(y)?;
+        Ok(self.curve.x_at_y(y))
+    }
+
+    /// The x values for each of the given y values.
+    pub fn quantiles(&self, ys: &[f32]) -> Result<Vec<f32>, JsError> {
+        ys.iter().map(|y| self.x_at_y(*y)).collect()
+    }
+
+    /// The x values for the y values of `QUANTILES`, i.e. 0.05, 0.25, 0.5, 0.75 and 0.95.
+    pub fn summary(&self) -> Vec<f32> {
+        QUANTILES.iter().map(|(_, y)| self.curve.x_at_y(*y)).collect()
+    }
+
+    #[wasm_bindgen(js_name = xValues)]
+    pub fn x_values(&self) -> Vec<f32> {
+        self.curve.get_values_as_vectors().0
+    }
+
+    #[wasm_bindgen(js_name = yValues)]
+    pub fn y_values(&self) -> Vec<f32> {
+        self.curve.get_values_as_vectors().1
+    }
+}
+
+/// A set of curves, each belonging to a key, between which curves can be interpolated.
+#[wasm_bindgen(js_name = CurveSet)]
+pub struct WasmCurveSet {
+    set: CurveSet<f32, IrregularDynamicCurve<f32, f32>>
+}
+
+#[wasm_bindgen(js_class = CurveSet)]
+impl WasmCurveSet {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self { set: CurveSet::new() }
+    }
+
+    #[wasm_bindgen(js_name = addCurve)]
+    pub fn add_curve(&mut self, key: f32, curve: &WasmCurve) -> Result<(), JsError> {
+        if !key.is_finite() || self.set.curves.iter().any(|(k, _)| *k == key) {
+            return Err(JsError::new(&format!("Invalid or duplicate key {}", key)));
+        }
+        self.set.add_curve(key, curve.curve.clone());
+        Ok(())
+    }
+
+    /// The keys, in ascending order.
+    pub fn keys(&self) -> Vec<f32> {
+        self.set.curves.iter().map(|(k, _)| *k).collect()
+    }
+
+    /// The curve for the given key, interpolated between the two nearest curves, or the
+    /// first or last curve if the key is out of bounds.
+    #[wasm_bindgen(js_name = curveAtX)]
+    pub fn curve_at_x(&self, x: f32) -> Result<WasmCurve, JsError> {
+        if self.set.curves.is_empty() {
+            return Err(JsError::new("Empty curve set"));
+        }
+        Ok(WasmCurve { curve: self.set.curve_at_x_with_continuation(x) })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> usize {
+        self.set.curves.len()
+    }
+}
+
+impl Default for WasmCurveSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wasm::*;
+    use crate::irregular_dynamic::Tup;
+
+    // only the success paths can be tested natively, because JsError needs a JavaScript host
+    #[test]
+    fn test_wasm() {
+        let bytes = IrregularDynamicCurve::<f32, f32>::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 100.0, y: 1.0 }]).serialize_compact();
+        let curve = WasmCurve::deserialize_compact(&bytes).unwrap();
+        assert_eq!(curve.y_at_x(50.0), 0.5);
+        assert_eq!(curve.x_at_y(0.25).unwrap(), 25.0);
+        assert_eq!(curve.summary(), vec![5.0, 25.0, 50.0, 75.0, 95.0]);
+        assert_eq!(curve.quantiles(&[0.1, 0.9]).unwrap(), vec![10.0, 90.0]);
+
+        let mut set = WasmCurveSet::new();
+        set.add_curve(0.0, &curve).unwrap();
+        let later = IrregularDynamicCurve::<f32, f32>::new(vec![Tup { x: 60.0, y: 0.0 }, Tup { x: 160.0, y: 1.0 }]).serialize_compact();
+        set.add_curve(60.0, &WasmCurve::deserialize_compact(&later).unwrap()).unwrap();
+        assert_eq!(set.keys(), vec![0.0, 60.0]);
+        assert_eq!(set.curve_at_x(30.0).unwrap().x_at_y(0.5).unwrap(), 80.0);
+        assert_eq!(set.curve_at_x(90.0).unwrap().min_x(), 60.0);
+    }
+}
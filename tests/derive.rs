@@ -0,0 +1,140 @@
+#![cfg(all(feature = "derive", feature = "msgpack"))]
+
+use dystonse_curves::tree::{TreeData, LeafData, SerdeFormat};
+use dystonse_curves::{CurveSet, Curve, IrregularDynamicCurve, Tup};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+type TestSet = CurveSet<f32, IrregularDynamicCurve<f32, f32>>;
+
+#[derive(TreeData, Serialize, Deserialize)]
+#[tree(collapsible)]
+struct StopPair {
+    arrival: TestSet,
+    departure: TestSet,
+    #[tree(leaf, rename = "overall curve")]
+    overall: IrregularDynamicCurve<f32, f32>,
+    #[tree(skip)]
+    #[serde(skip)]
+    cached_len: usize,
+}
+
+#[derive(TreeData)]
+#[tree(name = "Network")]
+struct Network {
+    stop_pairs: HashMap<String, StopPair>,
+}
+
+fn curve(offset: f32) -> IrregularDynamicCurve<f32, f32> {
+    IrregularDynamicCurve::new(vec![
+        Tup { x: offset, y: 0.0 },
+        Tup { x: offset + 50.0, y: 0.3 },
+        Tup { x: offset + 100.0, y: 1.0 },
+    ])
+}
+
+fn curve_set(offset: f32) -> TestSet {
+    let mut set = TestSet::new();
+    set.add_curve(0.0, curve(offset));
+    set.add_curve(10.0, curve(offset + 10.0));
+    set
+}
+
+#[test]
+fn test_derive_tree_data() {
+    let mut stop_pairs = HashMap::new();
+    for (i, name) in ["Hauptbahnhof -> Domsheide", "Domsheide -> Brill"].iter().enumerate() {
+        stop_pairs.insert(name.to_string(), StopPair {
+            arrival: curve_set(i as f32),
+            departure: curve_set(i as f32 + 5.0),
+            overall: curve(i as f32 + 20.0),
+            cached_len: 42,
+        });
+    }
+    let network = Network { stop_pairs };
+    assert_eq!(Network::NAME, "Network");
+    assert_eq!(StopPair::NAME, "StopPair");
+
+    let dir = std::env::temp_dir().join("dystonse-curves-test_derive_tree_data");
+    let dir_name = dir.to_str().unwrap();
+
+    for leaves in &[vec![], vec!["CurveSet"], vec!["StopPair"]] {
+        let _ = std::fs::remove_dir_all(&dir);
+        network.save_tree(dir_name, "network", &SerdeFormat::MessagePack, leaves).unwrap();
+        let loaded = Network::load_tree(dir_name, "network", &SerdeFormat::MessagePack, leaves).unwrap();
+
+        assert_eq!(loaded.stop_pairs.len(), network.stop_pairs.len());
+        for (name, stop_pair) in &network.stop_pairs {
+            let loaded_pair = &loaded.stop_pairs[name];
+            assert_eq!(loaded_pair.arrival.min_x(), stop_pair.arrival.min_x());
+            assert_eq!(loaded_pair.departure.max_x(), stop_pair.departure.max_x());
+            assert_eq!(loaded_pair.overall.get_x_values(), stop_pair.overall.get_x_values());
+            assert_eq!(loaded_pair.cached_len, 0);
+        }
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Like StopPair, but without #[tree(collapsible)].
+#[derive(TreeData, Serialize, Deserialize)]
+struct Trip {
+    stop_pairs: HashMap<String, TestSet>,
+}
+
+#[test]
+fn test_derive_not_collapsible() {
+    let mut trips = HashMap::new();
+    let mut stop_pairs = HashMap::new();
+    stop_pairs.insert("Domsheide -> Brill".to_string(), curve_set(0.0));
+    trips.insert("1000".to_string(), Trip { stop_pairs });
+
+    let dir = std::env::temp_dir().join("dystonse-curves-test_derive_not_collapsible");
+    let dir_name = dir.to_str().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // a Trip could only be saved as a directory, which the map would look for as a file
+    let leaves = vec!["Trip"];
+    let error = trips.save_tree(dir_name, "trips", &SerdeFormat::MessagePack, &leaves).err().unwrap();
+    assert!(error.to_string().contains("Trip"), "{}", error);
+    assert!(Trip::load_tree(dir_name, "1000", &SerdeFormat::MessagePack, &leaves).is_err());
+
+    // without the Trip among the leaves, it's saved as a directory and loaded from there
+    trips.save_tree(dir_name, "trips", &SerdeFormat::MessagePack, &vec![]).unwrap();
+    let loaded = HashMap::<String, Trip>::load_tree(dir_name, "trips", &SerdeFormat::MessagePack, &vec![]).unwrap();
+    assert_eq!(loaded["1000"].stop_pairs["Domsheide -> Brill"].min_x(), 0.0);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Like StopPair, but with a LeafData implementation of its own.
+#[derive(TreeData, Serialize, Deserialize)]
+#[tree(collapsible, no_leaf_data)]
+struct Stop {
+    departures: TestSet,
+}
+
+impl LeafData for Stop {
+    const VERSION : u32 = 2;
+
+    fn get_ext(_format: &SerdeFormat) -> &str {
+        "stop"
+    }
+}
+
+#[test]
+fn test_derive_no_leaf_data() {
+    let stop = Stop { departures: curve_set(0.0) };
+    let dir = std::env::temp_dir().join("dystonse-curves-test_derive_no_leaf_data");
+    let dir_name = dir.to_str().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let leaves = vec!["Stop"];
+    stop.save_tree(dir_name, "Domsheide", &SerdeFormat::MessagePack, &leaves).unwrap();
+    let bytes = std::fs::read(dir.join("Domsheide.stop")).unwrap();
+    assert!(bytes.starts_with(b"DCRV\x02\x00\x00\x00"));
+    let loaded = Stop::load_tree(dir_name, "Domsheide", &SerdeFormat::MessagePack, &leaves).unwrap();
+    assert_eq!(loaded.departures.max_x(), stop.departures.max_x());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}